@@ -0,0 +1,207 @@
+//! Actions that can be bound to key sequences
+//!
+//! Most bindings run a command, but some act on keybuddy itself,
+//! e.g. by switching to another mode, or type text. Several actions
+//! separated by ';' are performed one after the other.
+
+use std::fmt;
+use std::time::Duration;
+use anyhow::{Result, Context, bail};
+use quoted_string::strip_dquotes;
+
+use crate::uinput::{parse_combos, format_combos};
+
+/// What a binding does when its key sequence has been typed
+#[derive(Clone, PartialEq)]
+pub enum Action {
+    /// run a command: `"command"` or `run("command")`
+    Command(String),
+    /// terminate keybuddy
+    Quit,
+    /// read the config file again
+    Reload,
+    /// switch the device to another mode (keymap)
+    Mode(String),
+    /// return to the default mode and forget the partially typed
+    /// sequence
+    Reset,
+    /// type text as if it was entered on a keyboard
+    Type(String),
+    /// send key combinations through the virtual keyboard, e.g.
+    /// `key("ctrl+shift+t")`
+    Keys(Vec<Vec<u16>>),
+    /// pause before the next action of a list
+    Sleep(Duration),
+    /// write a message to stderr
+    Log(String),
+    /// several actions, performed in this order (never nested)
+    List(Vec<Action>),
+}
+
+/// Remove the double quotes around a string and resolve the escaped
+/// characters (\" and \\) within it
+fn unquote(text: &str) -> String {
+    let text = strip_dquotes(text).unwrap_or(text);
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.clone().next()) {
+            ('\\', Some(next @ ('"' | '\\'))) => {
+                result.push(next);
+                chars.next();
+            },
+            _ => result.push(ch),
+        }
+    }
+    result
+}
+
+/// Put double quotes around a string, escaping the characters that
+/// `unquote` resolves (\" and \\) and nothing else
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Split `text` at the separators ';' that are neither quoted nor
+/// within parentheses
+fn split_list(text: &str) -> Result<Vec<&str>> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, ch) in text.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ';' if !quoted && depth == 0 => {
+                parts.push(text[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    if quoted {
+        bail!("Missing closing quote in <{}>", text);
+    }
+    if depth != 0 {
+        bail!("Unbalanced parentheses in <{}>", text);
+    }
+    parts.push(text[start..].trim());
+    Ok(parts)
+}
+
+impl Action {
+    /// Parse the action part of a binding
+    ///
+    /// This is a command in double quotes, one of the built-in actions
+    /// `quit`, `reload`, `reset`, `mode("name")`, `type("text")`,
+    /// `key("combos")`, `sleep(ms)`, `log("message")`,
+    /// `run("command")`, or several of these separated by ';'.
+    ///
+    pub fn parse(text: &str) -> Result<Action> {
+        let mut actions = vec![];
+        for part in split_list(text)? {
+            if part.is_empty() {
+                bail!("Empty action in <{}>", text);
+            }
+            actions.push(Self::parse_single(part)?);
+        }
+        if actions.len() == 1 {
+            return Ok(actions.remove(0));
+        }
+        Ok(Action::List(actions))
+    }
+
+    /// Parse a single action (not a list)
+    fn parse_single(text: &str) -> Result<Action> {
+        if text.starts_with('"') {
+            if !text.ends_with('"') || text.len() < 2 {
+                bail!("Missing closing quote in <{}>", text);
+            }
+            return Ok(Action::Command(unquote(text)));
+        }
+        let (name, arg) = match text.split_once('(') {
+            Some((name, rest)) => {
+                let Some(arg) = rest.strip_suffix(')') else {
+                    bail!("Missing ')' in <{}>", text);
+                };
+                (name.trim(), Some(unquote(arg.trim())))
+            },
+            None => (text, None),
+        };
+        // `quit()` is the same as `quit`
+        let arg = arg.filter(|arg| !arg.is_empty());
+        let action = match (name, arg) {
+            ("quit" | "Quit", None) => Action::Quit,
+            ("reload", None) => Action::Reload,
+            ("reset", None) => Action::Reset,
+            ("quit" | "reload" | "reset", Some(_)) => bail!("'{}' takes no argument", name),
+            ("mode", Some(arg)) => Action::Mode(arg),
+            ("type", Some(arg)) => Action::Type(arg),
+            ("key", Some(arg)) => Action::Keys(parse_combos(&arg)?),
+            ("log", Some(arg)) => Action::Log(arg),
+            ("run", Some(arg)) => Action::Command(arg),
+            ("sleep", Some(arg)) => {
+                let ms = arg.parse::<u64>()
+                    .context(format!("Could not parse milliseconds: {}", arg))?;
+                Action::Sleep(Duration::from_millis(ms))
+            },
+            ("mode" | "type" | "key" | "log" | "run" | "sleep", None) => bail!("'{}' needs an argument", name),
+            _ => bail!("Unknown action '{}'", name),
+        };
+        Ok(action)
+    }
+
+    /// The actions to perform, in order: the elements of a list, or
+    /// the action itself
+    pub fn items(&self) -> &[Action] {
+        match self {
+            Action::List(actions) => actions,
+            action => std::slice::from_ref(action),
+        }
+    }
+
+    /// True if the action is handled by the dispatcher (mode switches
+    /// and resets)
+    pub fn is_internal(&self) -> bool {
+        matches!(self, Action::Mode(_) | Action::Reset)
+    }
+
+    /// The action with `f` applied to its text (the command, the name
+    /// of the mode, the text to type or the message)
+    pub fn map_text(&self, f: &dyn Fn(&str) -> String) -> Action {
+        match self {
+            Action::Command(command) => Action::Command(f(command)),
+            Action::Mode(name) => Action::Mode(f(name)),
+            Action::Type(text) => Action::Type(f(text)),
+            Action::Log(message) => Action::Log(f(message)),
+            Action::List(actions) => Action::List(actions.iter().map(|a| a.map_text(f)).collect()),
+            action => action.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Action {
+    /// Show the action in config file syntax
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Command(command) => write!(f, "{}", quote(command)),
+            Action::Quit => write!(f, "quit"),
+            Action::Reload => write!(f, "reload"),
+            Action::Mode(name) => write!(f, "mode({})", quote(name)),
+            Action::Reset => write!(f, "reset"),
+            Action::Type(text) => write!(f, "type({})", quote(text)),
+            Action::Keys(combos) => write!(f, "key({})", quote(&format_combos(combos))),
+            Action::Sleep(duration) => write!(f, "sleep({})", duration.as_millis()),
+            Action::Log(message) => write!(f, "log({})", quote(message)),
+            Action::List(actions) => {
+                let actions : Vec<String> = actions.iter().map(|a| format!("{:?}", a)).collect();
+                write!(f, "{}", actions.join("; "))
+            },
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Validation of a config file (`keybuddy check`)
+//!
+//! Besides the problems found while reading the file, this looks for
+//! device settings that keybuddy would refuse on startup, bindings
+//! that can never fire, mode switches to modes that do not exist, and
+//! commands whose executable cannot be found. No input device is
+//! touched.
+
+use std::collections::HashSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::ExitCode;
+use anyhow::{Result, bail};
+
+use crate::action::Action;
+use crate::config::{init_from_file, Config, ModeConfig, DEFAULT_MODE};
+use crate::hotplug::DeviceSpec;
+use crate::key_tree::{Binding, KTree, KeyStep};
+
+/// Words that the shell handles itself, so that no executable has to
+/// be found for commands starting with them
+const SHELL_WORDS: &[&str] = &[
+    "!", ".", ":", "[", "[[", "{", "(", "alias", "bg", "break", "builtin", "case",
+    "cd", "command", "continue", "echo", "eval", "exec", "exit", "export", "false",
+    "fg", "for", "function", "if", "jobs", "kill", "local", "printf", "pwd", "read",
+    "return", "set", "shift", "source", "test", "then", "trap", "true", "type",
+    "ulimit", "umask", "unset", "until", "wait", "while",
+];
+
+/// Where a binding was defined, as `file:line`
+fn location(binding: &Binding) -> String {
+    match &binding.origin {
+        Some((file, line)) => format!("{}:{}", file, line),
+        None => "?".to_string(),
+    }
+}
+
+/// Show a sequence in config file syntax
+fn show_steps(steps: &[KeyStep]) -> String {
+    let steps : Vec<String> = steps.iter().map(|s| s.to_string()).collect();
+    steps.join(", ")
+}
+
+/// True if `path` is a file that may be executed
+fn is_executable(path: &Path) -> bool {
+    path.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// The executable that `cmd` starts, if it can be determined
+///
+/// For commands run by the shell, leading variable assignments are
+/// skipped, and nothing is returned if the command starts with a word
+/// the shell handles itself or one that is only known at run time.
+///
+fn executable_of(cmd: &str, exec: bool) -> Option<String> {
+    let words = shlex::split(cmd)?;
+    let mut words = words.into_iter();
+    let word = if exec {
+        words.next()?
+    } else {
+        words.find(|w| !(w.contains('=') && !w.starts_with('=')))?
+    };
+    if !exec && SHELL_WORDS.contains(&word.as_str()) {
+        return None;
+    }
+    if word.contains(['$', '`', '(', '{', '*', '?']) {
+        return None;
+    }
+    Some(word)
+}
+
+/// True if the executable `name` exists, either as a path or in one of
+/// the directories of $PATH
+fn find_executable(name: &str) -> bool {
+    if name.contains('/') {
+        return is_executable(Path::new(name));
+    }
+    let path = std::env::var("PATH").unwrap_or_default();
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .any(|dir| is_executable(&Path::new(dir).join(name)))
+}
+
+/// Check the bindings of `tree`, which belongs to a device with the
+/// modes `modes`
+fn check_tree(tree: &KTree, modes: &[ModeConfig], quit: Option<&str>,
+              problems: &mut Vec<String>, notes: &mut Vec<String>) {
+    for bound in tree.bindings() {
+        let binding = bound.binding;
+        let at = location(binding);
+        let steps = show_steps(&bound.steps);
+        if bound.shadowed {
+            problems.push(format!("{}: {} never fires, an earlier wildcard binding matches the same keys",
+                                  at, steps));
+        }
+        if bound.prefix {
+            notes.push(format!("{}: {} starts longer sequences, so it only fires after 'delay'",
+                               at, steps));
+        }
+        for action in binding.action.items() {
+            match action {
+                Action::Mode(name) if name != DEFAULT_MODE && !modes.iter().any(|m| &m.name == name) => {
+                    problems.push(format!("{}: unknown mode '{}'", at, name));
+                },
+                Action::Command(cmd) if Some(cmd.as_str()) != quit => {
+                    if let Some(exe) = executable_of(cmd, binding.exec) {
+                        if !find_executable(&exe) {
+                            problems.push(format!("{}: '{}' not found in PATH", at, exe));
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// True if some binding of `tree` switches to mode `name`
+fn enters_mode(tree: &KTree, name: &str) -> bool {
+    tree.bindings()
+        .iter()
+        .any(|b| b.binding.action.items().iter().any(|a| matches!(a, Action::Mode(m) if m == name)))
+}
+
+/// Check the config read from `files`, report the problems found on
+/// stdout, and return a failure exit code if there are any
+///
+/// Sequences that start longer ones are only mentioned as notes.
+///
+pub fn check_config(files: &[String]) -> Result<ExitCode> {
+    if files.is_empty() {
+        bail!("No config file found");
+    }
+    let mut config = Config::new();
+    for file in files {
+        init_from_file(file, &mut config)?;
+    }
+    let mut problems : Vec<String> = config.errors.iter().chain(&config.warnings).map(|e| e.to_string()).collect();
+    let mut notes = vec![];
+
+    let quit = config.kv.get_str("quit_command").map(|x| x.to_string());
+    for dev in config.into_devices() {
+        if let Err(err) = DeviceSpec::from_config(0, &dev) {
+            problems.push(format!("device '{}': {:#}", dev.name, err));
+        }
+        check_tree(&dev.tree, &dev.modes, quit.as_deref(), &mut problems, &mut notes);
+        for mode in &dev.modes {
+            check_tree(&mode.tree, &dev.modes, quit.as_deref(), &mut problems, &mut notes);
+            let entered = enters_mode(&dev.tree, &mode.name)
+                || dev.modes.iter().any(|m| enters_mode(&m.tree, &mode.name));
+            if !entered {
+                for bound in mode.tree.bindings() {
+                    problems.push(format!("{}: {} never fires, mode '{}' of device '{}' is never entered",
+                                          location(bound.binding), show_steps(&bound.steps), mode.name, dev.name));
+                }
+            }
+        }
+    }
+
+    // devices share the bindings defined outside of [device] blocks,
+    // which are reported only once
+    let mut seen = HashSet::new();
+    problems.retain(|p| seen.insert(p.clone()));
+    notes.retain(|n| seen.insert(n.clone()));
+
+    for note in &notes {
+        println!("{} (note)", note);
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{}: OK", files.join(", "));
+        Ok(ExitCode::SUCCESS)
+    } else {
+        println!("{}: {} problem(s) found", files.join(", "), problems.len());
+        Ok(ExitCode::FAILURE)
+    }
+}
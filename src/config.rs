@@ -2,20 +2,26 @@
 //!
 //! Data are stored in a key-value store
 //! Key sequence data and associted commands in KTree
+//!
+//! Each `[device]` block of the config file has its own key-value
+//! store and KTree (see `Config`), and so does each `[mode]` block.
 
-use std::fs::File;
-use std::io::{prelude::*, BufReader};
-use std::sync::OnceLock;
-use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use regex::Regex;
 use quoted_string::strip_dquotes;
-use anyhow::{Result, Context};
+use anyhow::{Result, Context, bail};
 
+use crate::action::Action;
 use crate::key_codes::code_from_key_name;
-use crate::key_tree::{KTree, KeySequence};
+use crate::key_tree::{Binding, KTree, KeyClass, KeySequence, KeyStep, RunMode};
+use crate::toml_config::read_toml;
 
 /// Content of key-value store
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum KeyValueData {
     Float(f32),
     Int(isize),
@@ -34,39 +40,44 @@ fn strip_quotes_if_any(text: &str) -> &str {
 
 /// A simple key-value store that holds floats, integers, strings, or
 /// empty entries (None)
+#[derive(Clone)]
 pub struct KeyValueStore(pub HashMap<String, KeyValueData>);
 
-impl KeyValueStore {
-    /// Insert a key/value pair into the store
+impl KeyValueData {
+    /// Parse a value as written in the config file
     ///
-    /// The type of data stored is:
+    /// The type of data returned is:
     ///
     /// - Text:  if the value is enclosed in double quotes ("value")
     /// - None:  if the value is None (without quotes)
     /// - Float: if the value contains a decimal point ('.')
     /// - Int:   if none of the above applies
     ///
-    /// Returns Ok(()) when the value could be parsed
-    ///
-    pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
+    pub fn parse(value: &str) -> Result<Self> {
         if let Some(value) = strip_dquotes(value) {
-            self.0.insert(key.to_string(), KeyValueData::Text(value.to_string()));
+            Ok(KeyValueData::Text(value.to_string()))
         } else if value == "None" {
-            self.0.insert(key.to_string(), KeyValueData::None);
+            Ok(KeyValueData::None)
         } else if value.starts_with("0x") {
             let val = isize::from_str_radix(value.trim_start_matches("0x"), 16)
                 .context(format!("Could not parse value as hex: {}", value))?;
-            self.0.insert(key.to_string(), KeyValueData::Int(val));
+            Ok(KeyValueData::Int(val))
         } else if value.contains(".") {
             let val = value.parse::<f32>()
                 .context(format!("Could not parse value as float: {}", value))?;
-            self.0.insert(key.to_string(), KeyValueData::Float(val));
+            Ok(KeyValueData::Float(val))
         } else {
             let val = value.parse::<isize>()
                 .context(format!("Could not parse this value: {}", value))?;
-            self.0.insert(key.to_string(), KeyValueData::Int(val));
+            Ok(KeyValueData::Int(val))
         }
-        Ok(())
+    }
+}
+
+impl KeyValueStore {
+    /// Insert a key/value pair into the store
+    pub fn set(&mut self, key: &str, value: KeyValueData) {
+        self.0.insert(key.to_string(), value);
     }
 
     /// Retrieve a value from the store
@@ -104,6 +115,418 @@ impl KeyValueStore {
     }
 }
 
+/// Settings (`timeout`, `oneshot`) and key sequences of one `[mode]`
+/// block
+#[derive(Clone)]
+pub struct ModeConfig {
+    pub name: String,
+    pub kv: KeyValueStore,
+    pub tree: KTree,
+}
+
+/// Settings and key sequences of one `[device]` block
+pub struct DeviceConfig {
+    pub name: String,
+    pub kv: KeyValueStore,
+    pub tree: KTree,
+    pub modes: Vec<ModeConfig>,
+}
+
+/// Complete configuration
+///
+/// `kv` and `tree` hold what is defined outside of any `[device]`
+/// block. When a `[device]` block starts, its store and tree are
+/// initialized with copies of these, so that global settings and
+/// key sequences apply to all devices unless they are redefined
+/// within the block. The same holds for the modes in `modes`.
+///
+pub struct Config {
+    pub kv: KeyValueStore,
+    pub tree: KTree,
+    pub modes: Vec<ModeConfig>,
+    pub devices: Vec<DeviceConfig>,
+    /// problems found in the config file; the lines concerned have
+    /// been ignored
+    pub errors: Vec<ConfigError>,
+    /// lines of the config file that were used, but probably do not
+    /// do what was intended
+    pub warnings: Vec<ConfigError>,
+    /// the files read, and the patterns of included files
+    pub sources: Vec<PathBuf>,
+}
+
+impl Config {
+    /// Create a new empty configuration
+    pub fn new() -> Self {
+        Self {
+            kv: KeyValueStore(HashMap::<String, KeyValueData>::new()),
+            tree: KTree::new(),
+            modes: vec![],
+            devices: vec![],
+            errors: vec![],
+            warnings: vec![],
+            sources: vec![],
+        }
+    }
+
+    /// Return the configured devices
+    ///
+    /// If the config file contains no `[device]` block, a single
+    /// device named "default" is returned that uses the global
+    /// settings and key sequences.
+    ///
+    pub fn into_devices(self) -> Vec<DeviceConfig> {
+        if self.devices.is_empty() {
+            vec![DeviceConfig {
+                name: "default".to_string(),
+                kv: self.kv,
+                tree: self.tree,
+                modes: self.modes,
+            }]
+        } else {
+            self.devices
+        }
+    }
+}
+
+/// A problem found in a line of the config file
+#[derive(Clone, Debug)]
+pub struct ConfigError {
+    pub file: String,
+    /// line number, starting at 1
+    pub line: usize,
+    /// column where the problem was found, starting at 1
+    pub column: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    /// Show the error as `file:line:column: reason`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Type of value a setting takes
+#[derive(Clone, Copy, PartialEq)]
+enum ValueType {
+    Int,
+    /// an int or a float
+    Number,
+    /// a number that can be turned into a Duration (see `seconds`)
+    Seconds,
+    Text,
+}
+
+/// The settings keybuddy knows, with the type of their values
+const SETTINGS: &[(&str, ValueType)] = &[
+    ("delay", ValueType::Seconds),
+    ("long_press", ValueType::Seconds),
+    ("repeat_interval", ValueType::Seconds),
+    ("on_timeout", ValueType::Text),
+    ("on_error", ValueType::Text),
+    ("shell", ValueType::Text),
+    ("command_timeout", ValueType::Seconds),
+    ("kill_grace", ValueType::Seconds),
+    ("quit_command", ValueType::Text),
+    ("strict", ValueType::Number),
+    ("vid", ValueType::Int),
+    ("pid", ValueType::Int),
+    ("device_include", ValueType::Text),
+    ("device_exclude", ValueType::Text),
+    ("discovery", ValueType::Text),
+    ("isolation", ValueType::Text),
+];
+
+/// The settings of `[mode]` blocks
+const MODE_SETTINGS: &[(&str, ValueType)] = &[
+    ("timeout", ValueType::Seconds),
+    ("oneshot", ValueType::Number),
+];
+
+/// Type of the setting `key` (of a `[mode]` block if `in_mode`), or
+/// None if there is no such setting
+fn setting_type(key: &str, in_mode: bool) -> Option<ValueType> {
+    let settings = if in_mode { MODE_SETTINGS } else { SETTINGS };
+    settings.iter().find(|(name, _)| *name == key).map(|(_, expected)| *expected)
+}
+
+/// `seconds` as a Duration, if it is not negative and short enough
+/// to be added to the current time
+pub fn seconds(seconds: f32) -> Option<Duration> {
+    Duration::try_from_secs_f32(seconds)
+        .ok()
+        .filter(|duration| Instant::now().checked_add(*duration).is_some())
+}
+
+/// Check that `key` is a known setting (of a `[mode]` block if
+/// `in_mode`) and that `value` has the right type; returns the problem
+/// found, if any
+pub fn check_setting(key: &str, value: Option<&KeyValueData>, in_mode: bool) -> Option<String> {
+    let Some(expected) = setting_type(key, in_mode) else {
+        return Some(if in_mode {
+            format!("Unknown mode setting '{}'", key)
+        } else {
+            format!("Unknown setting '{}'", key)
+        });
+    };
+    let ok = match (expected, value?) {
+        (_, KeyValueData::None)
+        | (ValueType::Int, KeyValueData::Int(_))
+        | (ValueType::Number, KeyValueData::Int(_) | KeyValueData::Float(_))
+        | (ValueType::Text, KeyValueData::Text(_)) => true,
+        (ValueType::Seconds, KeyValueData::Int(v)) => seconds(*v as f32).is_some(),
+        (ValueType::Seconds, KeyValueData::Float(v)) => seconds(*v).is_some(),
+        _ => false,
+    };
+    let name = match expected {
+        ValueType::Int => "an integer",
+        ValueType::Number => "a number",
+        ValueType::Seconds => "a number of seconds (not negative, and not too large)",
+        ValueType::Text => "a string in double quotes",
+    };
+    (!ok).then(|| format!("'{}' should be {}, and is ignored", key, name))
+}
+
+/// Name of the mode that holds the key sequences outside of `[mode]`
+/// blocks
+pub const DEFAULT_MODE: &str = "default";
+
+/// The tree and key-value store of the `[mode]` block named `mode`,
+/// or, if there is none, `tree` and `kv` themselves
+fn section_of<'a>(tree: &'a mut KTree, kv: &'a mut KeyValueStore, modes: &'a mut [ModeConfig],
+                  mode: Option<&str>) -> (&'a mut KTree, &'a mut KeyValueStore) {
+    match mode.and_then(|name| modes.iter_mut().find(|m| m.name == name)) {
+        Some(m) => (&mut m.tree, &mut m.kv),
+        None => (tree, kv),
+    }
+}
+
+/// Where the definitions read are going
+#[derive(Default)]
+pub struct ParseState {
+    /// within a `[device]` block (the last one of `Config::devices`)
+    in_device: bool,
+    /// within a `[mode]` block
+    mode: Option<String>,
+    /// bindings defined in the current block so far
+    block: HashSet<(Arc<str>, usize)>,
+}
+
+/// The trees and key-value stores that definitions go to
+///
+/// Within a `[device]` block, this is the device's (or that of its
+/// current mode). Outside of any, it is the global one, followed by
+/// those of all devices defined so far (e.g. by an earlier config
+/// file), so that later files override earlier ones.
+///
+fn current_sections<'a>(config: &'a mut Config, state: &ParseState) -> Vec<(&'a mut KTree, &'a mut KeyValueStore)> {
+    let mode = state.mode.as_deref();
+    let Config { kv, tree, modes, devices, .. } = config;
+    if state.in_device && !devices.is_empty() {
+        let dev = devices.last_mut().unwrap();
+        return vec![section_of(&mut dev.tree, &mut dev.kv, &mut dev.modes, mode)];
+    }
+    let mut sections = vec![section_of(tree, kv, modes, mode)];
+    for dev in devices {
+        sections.push(section_of(&mut dev.tree, &mut dev.kv, &mut dev.modes, mode));
+    }
+    sections
+}
+
+/// Config file read by all users, before their own (without the
+/// extension, which is `.conf` or `.toml`)
+const SYSTEM_CONFIG: &str = "/etc/keybuddy";
+
+/// The config files to read, in this order: the system-wide file and
+/// the user's file (`$XDG_CONFIG_HOME/keybuddy.conf`, by default in
+/// `~/.config`), if they exist, and the file given on the command
+/// line
+///
+/// Definitions in later files override those in earlier ones. Where
+/// both a `.conf` and a `.toml` file exist, both are read, in this
+/// order.
+///
+pub fn config_files(cli: Option<&str>) -> Vec<String> {
+    let config_home = std::env::var("XDG_CONFIG_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .or_else(|| std::env::var("HOME").ok().map(|home| home + "/.config"));
+    let mut bases = vec![SYSTEM_CONFIG.to_string()];
+    if let Some(dir) = config_home {
+        bases.push(format!("{}/keybuddy", dir));
+    }
+    let mut files : Vec<String> = bases
+        .iter()
+        .flat_map(|base| [format!("{}.conf", base), format!("{}.toml", base)])
+        .collect();
+    files.retain(|f| Path::new(f).is_file());
+    if let Some(cli) = cli {
+        files.push(cli.to_string());
+    }
+    files
+}
+
+/// True if the file name `name` matches `pattern`, where '*' stands
+/// for any number of characters and '?' for a single one
+///
+/// As in the shell, names starting with '.' are only matched by
+/// patterns that do so as well.
+///
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    fn matches(pattern: &[char], name: &[char]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some('*'), _) => matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..])),
+            (Some('?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern : Vec<char> = pattern.chars().collect();
+    let name : Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+/// The files that the include directive for `pattern` refers to
+///
+/// Relative paths are relative to the directory of `including`, and a
+/// leading `~/` stands for the home directory. Wildcards are only
+/// supported in the file name; the files matching them are returned
+/// in alphabetical order.
+///
+fn resolve_include(pattern: &str, including: &Path) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let path = match pattern.strip_prefix("~/") {
+        Some(rest) => Path::new(&std::env::var("HOME").context("HOME is not set")?).join(rest),
+        None => including.parent().unwrap_or(Path::new(".")).join(pattern),
+    };
+    let name = path.file_name().map(|x| x.to_string_lossy().to_string()).unwrap_or_default();
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    if dir.to_string_lossy().contains(['*', '?']) {
+        bail!("Wildcards are only supported in the file name: {}", pattern);
+    }
+    if !name.contains(['*', '?']) {
+        if !path.is_file() {
+            bail!("Could not find included file {}", path.display());
+        }
+        return Ok((path.clone(), vec![path]));
+    }
+    let mut files : Vec<PathBuf> = std::fs::read_dir(&dir)
+        .context(format!("Could not read directory {}", dir.display()))?
+        .map_while(Result::ok)
+        .filter(|entry| glob_match(&name, &entry.file_name().to_string_lossy()))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok((path, files))
+}
+
+/// Set the options of a binding from a comma-separated list
+///
+/// Recognized options are:
+///
+/// - repeat
+///   fire again on every autorepeat event while the key is held, but
+///   at most once per `repeat_interval` seconds
+/// - repeat=0.2
+///   the same, with a specific minimum interval in seconds
+/// - exec
+///   split the command into words and execute it directly, instead of
+///   passing it to the shell
+/// - detach, wait, restart
+///   run the command in the background (default), wait until it has
+///   terminated before processing further keys, or kill the instance
+///   started previously by the same binding if it is still running
+/// - timeout=10
+///   terminate the command if it runs longer than this (in seconds,
+///   0 for no timeout), overriding the global `command_timeout`
+///
+fn parse_binding_options(opts: &str, binding: &mut Binding) -> Result<()> {
+    for opt in opts.split(",").map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let (name, value) = match opt.split_once("=") {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (opt, None),
+        };
+        match (name, value) {
+            ("repeat", None) => binding.repeat = true,
+            ("exec", None) => binding.exec = true,
+            ("detach", None) => binding.mode = RunMode::Detach,
+            ("wait", None) => binding.mode = RunMode::Wait,
+            ("restart", None) => binding.mode = RunMode::Restart,
+            ("repeat", Some(value)) => {
+                let interval = value.parse::<f32>()
+                    .context(format!("Could not parse repeat interval: {}", value))?;
+                binding.repeat = true;
+                binding.repeat_interval = Some(Duration::try_from_secs_f32(interval)
+                                               .context(format!("Not a valid repeat interval: {}", value))?);
+            },
+            ("timeout", Some(value)) => {
+                let timeout = value.parse::<f32>()
+                    .context(format!("Could not parse timeout: {}", value))?;
+                binding.timeout = Some(seconds(timeout)
+                                       .context(format!("Not a valid timeout: {}", value))?);
+            },
+            _ => bail!("Unknown binding option '{}'", opt),
+        }
+    }
+    Ok(())
+}
+
+/// Column (starting at 1) at which `part`, a slice of `line`, starts
+fn column_of(line: &str, part: &str) -> usize {
+    let offset = (part.as_ptr() as usize)
+        .checked_sub(line.as_ptr() as usize)
+        .filter(|offset| *offset <= line.len())
+        .unwrap_or(0);
+    line[..offset].chars().count() + 1
+}
+
+/// Parse one step of a key sequence: keys joined by '+', optionally
+/// followed by ':long' or ':tap', or a wildcard '<class>'
+///
+/// On error, the part of `text` at fault is returned along with the
+/// reason.
+///
+pub fn parse_step(text: &str) -> std::result::Result<KeyStep, (&str, String)> {
+    let (step, long) = match text.split_once(":") {
+        Some((step, modifier)) => match modifier.trim() {
+            "long" => (step, true),
+            "tap" => (step, false),
+            other => return Err((modifier.trim_start(), format!("Unknown key modifier '{}'", other))),
+        },
+        None => (text, false),
+    };
+    let step = step.trim();
+    if step.is_empty() {
+        return Err((text, "Empty key sequence step".to_string()));
+    }
+    if let Some(class) = step.strip_prefix("<").and_then(|x| x.strip_suffix(">")) {
+        return match KeyClass::from_name(class) {
+            Ok(class) => Ok(KeyStep::wildcard(class).with_long(long)),
+            Err(msg) => Err((step, msg.to_string())),
+        };
+    }
+    // replace text tokens by values
+    let mut keys = vec![];
+    for key in step.split("+") {
+        let key = key.trim();
+        if let Some(v) = code_from_key_name(key) {
+            keys.push(v);
+        } else if let Ok(v) = key.parse::<u16>() {
+            keys.push(v);
+        } else {
+            return Err((key, format!("Unknown key '{}'", key)));
+        }
+    }
+    Ok(KeyStep::new(&keys).with_long(long))
+}
+
 /// Add the definitions found in file `filename` to the key-value
 /// store or the tree of keystroke sequences.
 ///
@@ -114,20 +537,192 @@ impl KeyValueStore {
 ///   The value may be a string (in double quotes), None,
 ///   a float (with decimal point), or an int
 ///
-/// - code1, code2, code3 => "command"
+/// - code1, code2, code3 => "command" [option, option=value]
+///   code1, code2 => action; action
 ///   This defines a key sequence that is stored in the KTree
 ///   The code may be u16 values, or symbolic representations (e.g. KEY_KP0)
+///   Keys joined by '+' (e.g. KEY_KP0+KEY_KP5) form a chord, i.e. they
+///   must be held down together. A step followed by ':long' only
+///   matches if the keys are held longer than `long_press` seconds
+///   The options in brackets are optional (see `parse_binding_options`)
+///   Instead of a command, built-in actions can be given (see
+///   `Action::parse`)
+///
+/// - [device name]
+///   Starts a block with settings (e.g. vid, pid, device_include) and
+///   key sequences that only apply to one input device
+///
+/// - [mode name]
+///   Starts a block with the key sequences of a mode of the current
+///   device (or of all devices); `timeout` and `oneshot` set when
+///   the device returns to the default mode. `[mode default]` goes
+///   back to the key sequences of the device itself
+///
+/// - include "path"
+///   Reads the file at `path` (relative to the directory of this
+///   file) as if its lines were here; `path` may contain wildcards in
+///   the file name (e.g. "conf.d/*.conf") to include several files
 ///
 /// - # comment
 ///   ; comment
 ///   // comment
 ///   Comments and blank lines are ignored
 ///
-/// Returns Ok(()) and Err(_) when the file cannot be
-/// opened.  Invalid lines cause an error message to be printed, but
-/// the return value remains Ok(())
+/// Each file starts outside of any `[device]` block. Definitions
+/// made there also apply to the devices defined by files read before.
+///
+/// Returns Err(_) when the file cannot be opened. Invalid lines are
+/// skipped, and a `ConfigError` for each of them is added to
+/// `config.errors`. Sequences bound twice within a block and unknown
+/// or mistyped settings are added to `config.warnings`.
 ///
-pub fn init_from_file(filename: &str, tree: &mut KTree, kvstore: &mut KeyValueStore) -> Result<()> {
+pub fn init_from_file(filename: &str, config: &mut Config) -> Result<()> {
+    let mut state = ParseState::default();
+    read_file(Path::new(filename), config, &mut state, &mut vec![])
+}
+
+/// Read the definitions of `path`, which has been included by the
+/// files in `stack`
+///
+/// Files ending in `.toml` are read as TOML (see `toml_config`), all
+/// others in the format described at `init_from_file`.
+///
+pub fn read_file(path: &Path, config: &mut Config, state: &mut ParseState, stack: &mut Vec<PathBuf>) -> Result<()> {
+    let filename = path.to_string_lossy().to_string();
+    let bytes = std::fs::read(path).context(format!("Could not open file {}", filename))?;
+    let text = String::from_utf8_lossy(&bytes);
+    config.sources.push(path.to_path_buf());
+    stack.push(path.canonicalize().unwrap_or(path.to_path_buf()));
+    if is_toml(path) {
+        read_toml(&text, path, config, state, stack);
+    } else {
+        read_lines(&text, path, config, state, stack);
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// True if `path` names a TOML file
+pub fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "toml")
+}
+
+/// Read the files matching the include `pattern` of the file
+/// `including`, and return the problems found
+pub fn include(pattern: &str, including: &Path, config: &mut Config, state: &mut ParseState,
+               stack: &mut Vec<PathBuf>) -> Vec<String> {
+    let (watched, files) = match resolve_include(pattern, including) {
+        Ok(found) => found,
+        Err(msg) => return vec![format!("{:#}", msg)],
+    };
+    if files.len() != 1 || files[0] != watched {
+        // new files matching a pattern trigger a reload, too
+        config.sources.push(watched);
+    }
+    let mut problems = vec![];
+    for file in files {
+        let canonical = file.canonicalize().unwrap_or(file.clone());
+        if let Some(pos) = stack.iter().position(|f| *f == canonical) {
+            let cycle : Vec<String> = stack[pos..].iter().chain([&canonical])
+                .map(|f| f.display().to_string())
+                .collect();
+            problems.push(format!("Include cycle: {}", cycle.join(" -> ")));
+            continue;
+        }
+        if let Err(msg) = read_file(&file, config, state, stack) {
+            problems.push(format!("{:#}", msg));
+        }
+    }
+    problems
+}
+
+/// Start a `[device]` block named `name`, or with a generated name if
+/// `name` is empty
+pub fn begin_device(config: &mut Config, state: &mut ParseState, name: &str) {
+    let name = if name.is_empty() {
+        format!("device{}", config.devices.len() + 1)
+    } else {
+        name.to_string()
+    };
+    config.devices.push(DeviceConfig {
+        name,
+        kv: config.kv.clone(),
+        tree: config.tree.clone(),
+        modes: config.modes.clone(),
+    });
+    state.in_device = true;
+    state.mode = None;
+    state.block.clear();
+}
+
+/// Start a `[mode]` block named `name`; `DEFAULT_MODE` goes back to
+/// the definitions of the device itself
+pub fn begin_mode(config: &mut Config, state: &mut ParseState, name: &str) {
+    // a mode defined outside of [device] blocks belongs to all devices
+    let mut all_modes = vec![];
+    if state.in_device && !config.devices.is_empty() {
+        all_modes.push(&mut config.devices.last_mut().unwrap().modes);
+    } else {
+        all_modes.push(&mut config.modes);
+        all_modes.extend(config.devices.iter_mut().map(|dev| &mut dev.modes));
+    }
+    for modes in all_modes {
+        if name != DEFAULT_MODE && !modes.iter().any(|m| m.name == name) {
+            modes.push(ModeConfig {
+                name: name.to_string(),
+                kv: KeyValueStore(HashMap::new()),
+                tree: KTree::new(),
+            });
+        }
+    }
+    state.mode = (name != DEFAULT_MODE).then(|| name.to_string());
+}
+
+/// Bind `steps` to `binding` in the current block
+///
+/// Returns a warning if the sequence was already bound in the same
+/// block of the same file.
+///
+pub fn define_binding(config: &mut Config, state: &mut ParseState, steps: &[KeyStep], binding: Binding) -> Option<String> {
+    let origin = binding.origin.clone();
+    let mut previous = None;
+    for (i, (tree, _)) in current_sections(config, state).into_iter().enumerate() {
+        let replaced = tree.add(&KeySequence::from(&steps.to_vec()), Some(binding.clone()));
+        if i == 0 {
+            previous = replaced;
+        }
+    }
+    let origin = origin?;
+    // bindings inherited from outside the block or from other files
+    // may be redefined, but not those of the block itself
+    let previous = previous
+        .and_then(|b| b.origin)
+        .filter(|(file, _)| *file == origin.0)
+        .filter(|previous| state.block.contains(previous));
+    state.block.insert(origin);
+    previous.map(|(_, line)| format!("Sequence already bound on line {}, which is overridden", line))
+}
+
+/// Set `key` to `value` in the current block
+///
+/// Returns a warning if this is not a known setting or the value has
+/// the wrong type, in which case it is not set.
+///
+pub fn define_setting(config: &mut Config, state: &ParseState, key: &str, value: KeyValueData) -> Option<String> {
+    let in_mode = state.mode.is_some();
+    let problem = check_setting(key, Some(&value), in_mode);
+    if problem.is_some() && setting_type(key, in_mode).is_some() {
+        return problem;
+    }
+    for (_, kvstore) in current_sections(config, state) {
+        kvstore.set(key, value.clone());
+    }
+    problem
+}
+
+/// Read the definitions of a config file in the line format (see
+/// `init_from_file`)
+fn read_lines(text: &str, path: &Path, config: &mut Config, state: &mut ParseState, stack: &mut Vec<PathBuf>) {
     // match assignments
     static RX1: OnceLock<Regex> = OnceLock::new();
     let rx1 = RX1.get_or_init(
@@ -137,49 +732,182 @@ pub fn init_from_file(filename: &str, tree: &mut KTree, kvstore: &mut KeyValueSt
     // split line at "->"
     static RX2: OnceLock<Regex> = OnceLock::new();
     let rx2 = RX2.get_or_init(
-        || Regex::new(r##"^(.+?)\s*[=-]{1,2}>\s*(Quit|".+")$"##).unwrap()
+        || Regex::new(r##"^([^"=]+?)\s*[=-]{1,2}>\s*(?P<action>.+?)\s*(?:\[(?P<opts>[^\]"]*)\])?$"##).unwrap()
+    );
+
+    // section headers
+    static RX3: OnceLock<Regex> = OnceLock::new();
+    let rx3 = RX3.get_or_init(
+        || Regex::new(r##"^\[\s*(?P<kind>[a-z]+)(?:\s+(?P<name>[^\]]*?))?\s*\]\s*$"##).unwrap()
     );
 
-    let h = File::open(filename).context(format!("Could not open file {}", filename))?;
-    let reader = BufReader::new(h);
-    for line in reader.lines() {
-        if let Ok(line) = line {
-            if line.trim().is_empty()
-                || line.starts_with("#")
-                || line.starts_with(";")
-                || line.starts_with("//") {
-                    // skip comments and empty lines
-                } else if let Some(caps) = rx2.captures(&line) {
-                    // normal line with key sequence
-                    let keys : Vec<String> = caps[1]
-                        .split(",")
-                        .map(|x| x.trim().to_string())
-                        .collect();
-                    // replace text tokens by values
-                    let keys : Vec<u16> =
-                        keys.iter().map(|x| -> u16 {
-                            if let Some(v) = code_from_key_name(x) {
-                                v
-                            } else if let Ok(v) = x.parse::<u16>() {
-                                v
-                            } else {
-                                eprintln!("Cannot interpret key code '{}'", x);
-                                0 as u16
-                            }
-                        }).collect();
-                    tree.add(&KeySequence::from(&keys),
-                             Some(strip_quotes_if_any(caps[2].trim()).to_string()));
-                } else if let Some(caps) = rx1.captures(&line) {
-                    // add data to key-value store
-                    let key = &caps["key"];
-                    let val = &caps["val"];
-                    if let Err(msg) = kvstore.add(key.trim(), val.trim()) {
-                        eprintln!("Error: {} !", msg);
-                    }
-                } else {
-                    eprintln!("Could not parse line <{:?}>", rx1);
+    // include directives
+    static RX4: OnceLock<Regex> = OnceLock::new();
+    let rx4 = RX4.get_or_init(
+        || Regex::new(r##"^include\s+"(?P<path>[^"]+)"\s*$"##).unwrap()
+    );
+
+    let filename = path.to_string_lossy().to_string();
+    let origin_file : Arc<str> = Arc::from(filename.as_str());
+    for (index, line) in text.lines().enumerate() {
+        // a problem found at `part` of the line
+        let at = |part: &str, reason: String| ConfigError {
+            file: filename.clone(),
+            line: index + 1,
+            column: column_of(line, part),
+            reason,
+        };
+        if line.trim().is_empty()
+            || line.starts_with("#")
+            || line.starts_with(";")
+            || line.starts_with("//") {
+            // skip comments and empty lines
+        } else if let Some(caps) = rx4.captures(line) {
+            // the included files are read as if their lines were here
+            let pattern = caps.name("path").unwrap().as_str();
+            for problem in include(pattern, path, config, state, stack) {
+                config.errors.push(at(pattern, problem));
+            }
+        } else if let Some(caps) = rx3.captures(line) {
+            // start of a new section
+            let name = caps.name("name").map_or("", |m| m.as_str());
+            match &caps["kind"] {
+                "device" => begin_device(config, state, strip_quotes_if_any(name)),
+                "mode" if name.is_empty() => config.errors.push(at(line, "Mode without name".to_string())),
+                "mode" => begin_mode(config, state, strip_quotes_if_any(name)),
+                kind => config.errors.push(at(caps.name("kind").unwrap().as_str(), format!("Unknown section type '{}'", kind))),
+            }
+        } else if let Some(caps) = rx2.captures(line) {
+            // normal line with key sequence; steps are separated by
+            // commas, the keys of a chord by '+'; a step may end in
+            // ':long' (held) or ':tap', and '<class>' is a wildcard
+            let steps : std::result::Result<Vec<KeyStep>, _> = caps
+                .get(1)
+                .unwrap()
+                .as_str()
+                .split(",")
+                .map(parse_step)
+                .collect();
+            let steps = match steps {
+                Ok(steps) => steps,
+                Err((part, msg)) => {
+                    config.errors.push(at(part, msg));
+                    continue;
+                }
+            };
+            let action_text = caps.name("action").unwrap().as_str();
+            let action = match Action::parse(action_text) {
+                Ok(action) => action,
+                Err(msg) => {
+                    config.errors.push(at(action_text, format!("{:#}", msg)));
+                    continue;
                 }
+            };
+            let mut binding = Binding::new(action);
+            binding.origin = Some((origin_file.clone(), index + 1));
+            if let Some(opts) = caps.name("opts") {
+                if let Err(msg) = parse_binding_options(opts.as_str(), &mut binding) {
+                    config.errors.push(at(opts.as_str(), format!("{:#}", msg)));
+                    continue;
+                }
+            }
+            // definitions go to the current [device] and [mode] block,
+            // if any
+            if let Some(msg) = define_binding(config, state, &steps, binding) {
+                config.warnings.push(at(caps.get(1).unwrap().as_str(), msg));
+            }
+        } else if let Some(caps) = rx1.captures(line) {
+            // add data to key-value store
+            let key = caps.name("key").unwrap().as_str();
+            let val = caps.name("val").unwrap().as_str();
+            match KeyValueData::parse(val.trim()) {
+                Ok(value) => if let Some(msg) = define_setting(config, state, key, value) {
+                    config.warnings.push(at(key, msg));
+                },
+                Err(msg) => config.errors.push(at(val, format!("{:#}", msg))),
+            }
+        } else {
+            config.errors.push(at(line, "Could not parse line".to_string()));
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for the files of test `name`
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("keybuddy-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write the `files` (path relative to `dir`, content) and read
+    /// the first one
+    fn read(dir: &Path, files: &[(&str, &str)]) -> Config {
+        for (name, text) in files {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+        let mut config = Config::new();
+        init_from_file(&dir.join(files[0].0).to_string_lossy(), &mut config).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        config
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*.conf", "a.conf"));
+        assert!(!glob_match("*.conf", ".conf.conf"));
+        assert!(glob_match("a?c", "abc"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("a*b*c", "aXXbYc"));
+        assert!(!glob_match("a*b*c", "aXXbY"));
+        assert!(!glob_match("*.conf", "a.conf.bak"));
+
+        // names starting with '.' need a pattern that does, too
+        assert!(!glob_match("*", ".hidden"));
+        assert!(!glob_match("?hidden", ".hidden"));
+        assert!(glob_match(".*", ".hidden"));
+    }
+
+    #[test]
+    fn relative_includes() {
+        let dir = test_dir("relative");
+        let config = read(&dir, &[
+            ("main.conf", "include \"sub/b.conf\"\ninclude \"conf.d/*.conf\"\n"),
+            // relative to the directory of the including file
+            ("sub/b.conf", "include \"c.conf\"\n"),
+            ("sub/c.conf", "on_error = \"c\"\n"),
+            // read in alphabetical order, without hidden files
+            ("conf.d/2.conf", "shell = \"two\"\n"),
+            ("conf.d/1.conf", "shell = \"one\"\n"),
+            ("conf.d/.3.conf", "shell = \"hidden\"\n"),
+            ("conf.d/notes.txt", "shell = \"text\"\n"),
+        ]);
+        assert!(config.errors.is_empty(), "{:?}", config.errors);
+        assert_eq!(config.kv.get_str("on_error"), Some("c"));
+        assert_eq!(config.kv.get_str("shell"), Some("two"));
+        // the pattern is watched for new files
+        assert!(config.sources.contains(&dir.join("conf.d/*.conf")));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = test_dir("cycle");
+        let config = read(&dir, &[
+            ("a.conf", "include \"b.conf\"\nshell = \"a\"\n"),
+            ("b.conf", "include \"a.conf\"\non_error = \"b\"\n"),
+        ]);
+        assert_eq!(config.errors.len(), 1, "{:?}", config.errors);
+        assert!(config.errors[0].reason.starts_with("Include cycle: "), "{}", config.errors[0]);
+        assert!(config.errors[0].file.ends_with("b.conf"));
+        // everything else is still read
+        assert_eq!(config.kv.get_str("shell"), Some("a"));
+        assert_eq!(config.kv.get_str("on_error"), Some("b"));
+    }
 }
@@ -0,0 +1,666 @@
+//! Dispatcher that turns key strokes into commands
+//!
+//! Key strokes from all devices arrive through a single channel,
+//! tagged with the index of their device. Every device has its own
+//! KTree and its own partially typed key sequence.
+//!
+//! A step of a sequence is normally completed when its key is
+//! pressed. If the tree contains a chord that includes all keys
+//! currently held down, the dispatcher waits: the step is completed
+//! when the held keys match the chord exactly, or when one of them is
+//! released.
+//!
+//! Likewise, if a long-press step (`KEY_KP1:long`) could follow, the
+//! step is only completed when the key is released; the time stamps
+//! of the press and release events then tell a tap from a hold.
+//!
+//! Autorepeat events only fire bindings that have the `repeat`
+//! option, and only while the keys of the step that fired them are
+//! held down.
+//!
+//! If a complete sequence is also the prefix of a longer one (e.g.
+//! `KEY_ESC` and `KEY_ESC, KEY_KP1`), its binding is deferred: it runs
+//! when the sequence delay expires without another key, or when the
+//! next key does not continue any longer sequence.
+//!
+//! The sequence delay is enforced by a timer: the main loop waits
+//! until `Dispatcher::deadline` and then calls `Dispatcher::expire`,
+//! which resets partially typed sequences and runs the deferred
+//! bindings or the `on_timeout` command.
+//!
+//! Bindings are returned as `Trigger`s, which also record the key
+//! strokes that fired them; these are passed to the command in
+//! environment variables.
+//!
+//! Besides its default keymap, a device can have modes with keymaps
+//! of their own. The actions `mode("name")`, which switches modes,
+//! and `reset` are performed here; bindings consisting only of these
+//! are never returned. A mode is left again
+//! after its `timeout` without key strokes, or, for a `oneshot` mode,
+//! after one binding has fired or the sequence has expired.
+
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::action::Action;
+use crate::config::{DeviceConfig, DEFAULT_MODE};
+use crate::key_codes::key_name_from_code;
+use crate::key_reader::{KeyEvent, KeyState};
+use crate::key_tree::{Binding, KTree, KeySequence, KeyStep};
+
+/// A binding to run, together with the key strokes that fired it
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    pub binding: Binding,
+    /// steps of the sequence that fired the binding
+    pub steps: Vec<KeyStep>,
+    /// event node of the device
+    pub device: String,
+    pub mode: String,
+    /// kernel time stamp of the last key stroke
+    pub time: Duration,
+}
+
+impl Trigger {
+    /// Environment variables describing the key strokes
+    ///
+    /// KEYBUDDY_SEQUENCE holds the steps in config file syntax
+    /// (`KEY_KPSLASH KEY_KP1+KEY_KP2`), KEYBUDDY_CODES the same steps as
+    /// key codes (`98 79+80`), KEYBUDDY_DEVICE the event node,
+    /// KEYBUDDY_MODE the current mode, and KEYBUDDY_TIMESTAMP the time
+    /// of the last key stroke in seconds since the epoch.
+    ///
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let sequence : Vec<String> = self.steps
+            .iter()
+            .map(|step| step.to_string())
+            .collect();
+        let codes : Vec<String> = self.steps
+            .iter()
+            .map(|step| step.keys()
+                 .iter()
+                 .map(|k| k.to_string())
+                 .collect::<Vec<_>>()
+                 .join("+"))
+            .collect();
+        vec![
+            ("KEYBUDDY_SEQUENCE", sequence.join(" ")),
+            ("KEYBUDDY_CODES", codes.join(" ")),
+            ("KEYBUDDY_DEVICE", self.device.clone()),
+            ("KEYBUDDY_MODE", self.mode.clone()),
+            ("KEYBUDDY_TIMESTAMP", format!("{}.{:06}", self.time.as_secs(), self.time.subsec_micros())),
+        ]
+    }
+
+    /// True if `key` belongs to the step that completed the sequence
+    fn holds(&self, key: u16) -> bool {
+        self.steps.last().is_some_and(|step| step.keys().contains(&key))
+    }
+}
+
+/// A keymap of a device
+struct Mode {
+    name: String,
+    tree: KTree,
+    /// return to the default mode when no key is pressed for this long
+    timeout: Option<Duration>,
+    /// return to the default mode after one binding has fired
+    oneshot: bool,
+}
+
+/// The modes of a device; the default mode comes first
+fn modes_of(dev: &DeviceConfig) -> Vec<Mode> {
+    let mut modes = vec![Mode {
+        name: DEFAULT_MODE.to_string(),
+        tree: dev.tree.clone(),
+        timeout: None,
+        oneshot: false,
+    }];
+    modes.extend(dev.modes.iter().map(|mode| Mode {
+        name: mode.name.clone(),
+        tree: mode.tree.clone(),
+        timeout: mode.kv.get_float("timeout")
+            .filter(|t| *t > 0.0)
+            .and_then(|t| Duration::try_from_secs_f32(t).ok()),
+        oneshot: mode.kv.get_float("oneshot").is_some_and(|x| x != 0.0),
+    }));
+    modes
+}
+
+/// Key sequence state of one device
+struct DeviceState {
+    name: String,
+    modes: Vec<Mode>,
+    /// index of the current mode
+    mode: usize,
+    key_memory_span: f32,
+    long_press: Duration,
+    repeat_interval: Duration,
+    seq: Vec<KeyStep>,
+    now: Instant,
+    newline: bool,
+    /// keys currently held down
+    held: Vec<u16>,
+    /// keys pressed while waiting for a chord to be completed
+    pending: Vec<u16>,
+    /// time stamp of the first pending key
+    pressed_at: Duration,
+    /// event node and time stamp of the last key event
+    node: Arc<str>,
+    time: Duration,
+    /// binding that fires on autorepeat, and the time it last fired
+    repeating: Option<(Trigger, Instant)>,
+    /// binding of a sequence that is also the prefix of a longer one
+    deferred: Option<Trigger>,
+    /// binding to run when a partially typed sequence expires
+    on_timeout: Option<Binding>,
+    debug: bool,
+}
+
+impl DeviceState {
+    /// The keymap of the current mode
+    fn tree(&self) -> &KTree {
+        &self.modes[self.mode].tree
+    }
+
+    /// Trigger for `binding`, fired by the current sequence
+    fn trigger(&self, binding: Binding) -> Trigger {
+        Trigger {
+            binding,
+            steps: self.seq.clone(),
+            device: self.node.to_string(),
+            mode: self.modes[self.mode].name.clone(),
+            time: self.time,
+        }
+    }
+
+    /// Switch to the mode called `name`
+    fn switch_mode(&mut self, name: &str) {
+        let Some(mode) = self.modes.iter().position(|m| m.name == name) else {
+            eprintln!("{}: unknown mode '{}'", self.name, name);
+            return;
+        };
+        if self.debug && mode != self.mode {
+            eprintln!("-> mode {}", name);
+        }
+        self.mode = mode;
+        self.seq.clear();
+        self.deferred = None;
+        self.now = Instant::now();
+    }
+
+    /// Return to the default mode and forget the partially typed
+    /// sequence and held keys
+    fn reset(&mut self) {
+        self.switch_mode(DEFAULT_MODE);
+        self.pending.clear();
+        self.repeating = None;
+        self.newline = true;
+    }
+
+    /// Perform the mode switches and resets of `trigger`, and return
+    /// it for execution if it has other actions
+    ///
+    /// A binding without mode switch ends a one-shot mode.
+    ///
+    fn fire(&mut self, trigger: Trigger) -> Vec<Trigger> {
+        let oneshot = self.modes[self.mode].oneshot;
+        let mut switched = false;
+        for action in trigger.binding.action.items() {
+            match action {
+                Action::Mode(name) => self.switch_mode(name),
+                Action::Reset => self.reset(),
+                _ => continue,
+            }
+            switched = true;
+        }
+        if oneshot && !switched {
+            self.switch_mode(DEFAULT_MODE);
+        }
+        if trigger.binding.action.items().iter().all(|action| action.is_internal()) {
+            return vec![];
+        }
+        vec![trigger]
+    }
+
+    /// Append `step` to the sequence and return the bindings to run
+    ///
+    /// This is the binding of the sequence if it is complete, and
+    /// possibly a deferred binding that can no longer be extended.
+    ///
+    fn push_step(&mut self, step: KeyStep) -> Vec<Trigger> {
+        self.seq.push(step.clone());
+        let seq = KeySequence::from(&self.seq);
+        let ambiguous = !self.tree().next_steps(&seq).is_empty();
+        if let Some(binding) = self.tree().find(&seq) {
+            let trigger = self.trigger(binding);
+            if ambiguous {
+                // wait whether the longer sequence follows
+                self.deferred = Some(trigger);
+                self.newline = false;
+                return vec![];
+            }
+            if trigger.binding.repeat {
+                self.repeating = Some((trigger.clone(), Instant::now()));
+            }
+            self.deferred = None;
+            self.seq.clear();
+            self.newline = true;
+            self.fire(trigger)
+        } else if !ambiguous && self.deferred.is_some() {
+            // the longer sequence did not follow: run the shorter one,
+            // and start over with this step (in the mode it selects)
+            let deferred = self.deferred.take().unwrap();
+            self.seq.clear();
+            let mut found = self.fire(deferred);
+            found.extend(self.push_step(step));
+            found
+        } else {
+            self.newline = false;
+            vec![]
+        }
+    }
+
+    /// Time at which the partially typed sequence expires, if there
+    /// is one
+    ///
+    /// Sequences do not expire while keys of a chord or long press are
+    /// still held down.
+    ///
+    fn seq_deadline(&self) -> Option<Instant> {
+        if self.newline || !self.pending.is_empty() {
+            return None;
+        }
+        Duration::try_from_secs_f32(self.key_memory_span)
+            .ok()
+            .and_then(|delay| self.now.checked_add(delay))
+    }
+
+    /// Time at which the device returns to the default mode, if the
+    /// current mode has a timeout
+    fn mode_deadline(&self) -> Option<Instant> {
+        if !self.pending.is_empty() {
+            return None;
+        }
+        self.modes[self.mode].timeout.and_then(|timeout| self.now.checked_add(timeout))
+    }
+
+    /// Time at which the sequence or the mode expires
+    fn deadline(&self) -> Option<Instant> {
+        self.seq_deadline().into_iter().chain(self.mode_deadline()).min()
+    }
+
+    /// Reset the partially typed sequence if its delay has expired,
+    /// and return the deferred binding or the `on_timeout` binding;
+    /// return to the default mode if the mode has expired
+    fn expire(&mut self) -> Vec<Trigger> {
+        let now = Instant::now();
+        let mut found = vec![];
+        if self.seq_deadline().is_some_and(|deadline| deadline <= now) {
+            self.newline = true;
+            if let Some(trigger) = self.deferred.take() {
+                self.seq.clear();
+                found.extend(self.fire(trigger));
+            } else {
+                if self.debug {
+                    eprintln!("... aborted");
+                }
+                // on_timeout sees the aborted sequence
+                found.extend(self.on_timeout.clone().map(|binding| self.trigger(binding)));
+                self.seq.clear();
+                if self.modes[self.mode].oneshot {
+                    self.switch_mode(DEFAULT_MODE);
+                }
+            }
+        }
+        if self.mode_deadline().is_some_and(|deadline| deadline <= now) {
+            self.switch_mode(DEFAULT_MODE);
+        }
+        found
+    }
+
+    /// Complete the step for the pending keys: as a chord if the tree
+    /// contains one with exactly these keys, otherwise key by key
+    ///
+    /// With `long` set, a long-press step is preferred over a tap.
+    fn flush_pending(&mut self, long: bool) -> Vec<Trigger> {
+        let pending = std::mem::take(&mut self.pending);
+        let chord = KeyStep::new(&pending).with_long(long);
+        let next = self.tree().next_steps(&KeySequence::from(&self.seq));
+        if next.iter().any(|step| step.matches(&chord)) {
+            return self.push_step(chord);
+        }
+        let chord = chord.with_long(false);
+        if next.iter().any(|step| step.matches(&chord)) {
+            return self.push_step(chord);
+        }
+        let mut found = vec![];
+        for k in pending {
+            found.extend(self.push_step(KeyStep::new(&[k])));
+        }
+        found
+    }
+}
+
+/// Shared dispatcher for the key strokes of all devices
+pub struct Dispatcher {
+    devices: Vec<DeviceState>,
+    debug: bool,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher for `devices`, in the order in which their
+    /// events are tagged
+    ///
+    /// The maximum time span between keystrokes that form a sequence
+    /// is taken from the device's `delay` setting, or
+    /// `key_memory_span` if there is none. Keys held longer than
+    /// `long_press` seconds (default 0.5) count as long presses.
+    /// Bindings with the `repeat` option fire at most once per
+    /// `repeat_interval` seconds (default 0, i.e. on every autorepeat
+    /// event). The command `on_timeout`, if set, runs whenever a
+    /// partially typed sequence expires. Every device starts in its
+    /// default mode.
+    ///
+    pub fn new(devices: &[DeviceConfig], key_memory_span: f32, debug: bool) -> Self {
+        let devices = devices
+            .iter()
+            .map(|dev| DeviceState {
+                name: dev.name.clone(),
+                modes: modes_of(dev),
+                mode: 0,
+                key_memory_span: dev.kv.get_float("delay").unwrap_or(key_memory_span),
+                long_press: Duration::from_secs_f32(dev.kv.get_float("long_press").unwrap_or(0.5)),
+                repeat_interval: Duration::from_secs_f32(dev.kv.get_float("repeat_interval").unwrap_or(0.0)),
+                seq: vec![],
+                now: Instant::now(),
+                newline: true,
+                held: vec![],
+                pending: vec![],
+                pressed_at: Duration::ZERO,
+                node: Arc::from(""),
+                time: Duration::ZERO,
+                repeating: None,
+                deferred: None,
+                on_timeout: dev.kv.get_str("on_timeout").map(|cmd| Binding::new(Action::Command(cmd.to_string()))),
+                debug,
+            })
+            .collect();
+        Self { devices, debug }
+    }
+
+    /// Dump the trees of all devices and modes to stderr
+    pub fn dump(&self) {
+        for dev in &self.devices {
+            if self.devices.len() > 1 {
+                eprintln!("device {}:", dev.name);
+            }
+            for mode in &dev.modes {
+                if dev.modes.len() > 1 {
+                    eprintln!("mode {}:", mode.name);
+                }
+                mode.tree.dump();
+            }
+        }
+    }
+
+    /// Name of the device that produced `ev`
+    pub fn device_name(&self, ev: &KeyEvent) -> &str {
+        self.devices.get(ev.source).map_or("?", |dev| dev.name.as_str())
+    }
+
+    /// Time at which the next partially typed sequence or mode of any
+    /// device expires, if there is one
+    pub fn deadline(&self) -> Option<Instant> {
+        self.devices
+            .iter()
+            .filter_map(|dev| dev.deadline())
+            .min()
+    }
+
+    /// Reset the sequences and modes that have expired, and return
+    /// the deferred or `on_timeout` bindings to run
+    pub fn expire(&mut self) -> Vec<Trigger> {
+        self.devices
+            .iter_mut()
+            .flat_map(|dev| dev.expire())
+            .collect()
+    }
+
+    /// Process one key event and return the bindings to execute
+    ///
+    /// These are the bindings of completed key sequences of the
+    /// event's device, or a binding that is repeated.
+    ///
+    pub fn handle(&mut self, ev: &KeyEvent) -> Vec<Trigger> {
+        let multiple = self.devices.len() > 1;
+        let Some(dev) = self.devices.get_mut(ev.source) else {
+            return vec![];
+        };
+        let k = ev.code;
+        dev.node = ev.device.clone();
+        dev.time = ev.time;
+
+        if ev.state == KeyState::Repeated {
+            let Some((trigger, last)) = dev.repeating.as_mut() else {
+                return vec![];
+            };
+            let interval = trigger.binding.repeat_interval.unwrap_or(dev.repeat_interval);
+            if !trigger.holds(k) || last.elapsed() < interval {
+                return vec![];
+            }
+            *last = Instant::now();
+            trigger.time = ev.time;
+            if self.debug {
+                eprint!("(repeat) ");
+            }
+            return vec![trigger.clone()];
+        }
+
+        if ev.state == KeyState::Released {
+            dev.held.retain(|x| *x != k);
+            if dev.repeating.as_ref().is_some_and(|(trigger, _)| trigger.holds(k)) {
+                dev.repeating = None;
+            }
+            if dev.pending.is_empty() {
+                return vec![];
+            }
+            // a chord was started but not completed, or a long press
+            // was possible
+            let long = ev.time.saturating_sub(dev.pressed_at) >= dev.long_press;
+            return dev.flush_pending(long);
+        }
+
+        // in case the timer has not fired yet
+        let mut found = dev.expire();
+        if self.debug {
+            if multiple && dev.seq.is_empty() && dev.pending.is_empty() {
+                eprint!("{}: ", dev.name);
+            }
+            if let Some(name) = key_name_from_code(k) {
+                eprint!("{} ",name);
+            } else {
+                eprint!("k={}", k);
+            }
+        }
+        dev.now = Instant::now();
+        dev.repeating = None;
+        if !dev.held.contains(&k) {
+            dev.held.push(k);
+        }
+        if dev.pending.is_empty() {
+            dev.pressed_at = ev.time;
+        }
+        if !dev.pending.contains(&k) {
+            dev.pending.push(k);
+        }
+
+        // compare the keys held down with the possible next steps
+        let held = KeyStep::new(&dev.held);
+        let next = dev.tree().next_steps(&KeySequence::from(&dev.seq));
+        if next.iter().any(|step| step.extends(&held)) {
+            // a chord containing the held keys, or a long press of
+            // these keys, might follow
+            return found;
+        }
+        if held.keys().len() > 1 && next.iter().any(|step| step.matches(&held)) {
+            dev.pending.clear();
+            found.extend(dev.push_step(held));
+        } else {
+            found.extend(dev.flush_pending(false));
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{init_from_file, Config};
+    use crate::key_codes::code_from_key_name;
+
+    /// Dispatcher for the config `text`, with the sequence delay
+    /// `delay` unless the config sets one
+    fn dispatcher(name: &str, text: &str, delay: f32) -> Dispatcher {
+        let path = std::env::temp_dir().join(format!("keybuddy-test-{}-{}.conf", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let mut config = Config::new();
+        init_from_file(&path.to_string_lossy(), &mut config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(config.errors.is_empty(), "{:?}", config.errors);
+        Dispatcher::new(&config.into_devices(), delay, false)
+    }
+
+    /// The commands of the bindings in `triggers`
+    fn commands(triggers: Vec<Trigger>) -> Vec<String> {
+        triggers
+            .into_iter()
+            .map(|trigger| match trigger.binding.action {
+                Action::Command(cmd) => cmd,
+                action => panic!("unexpected action {:?}", action),
+            })
+            .collect()
+    }
+
+    /// Feed an event for the key `name` at `secs` (kernel time) and
+    /// return the commands to run
+    fn send(dispatcher: &mut Dispatcher, name: &str, state: KeyState, secs: f32) -> Vec<String> {
+        let ev = KeyEvent {
+            source: 0,
+            device: Arc::from("/dev/input/event0"),
+            code: code_from_key_name(name).unwrap(),
+            state,
+            time: Duration::from_secs_f32(secs),
+        };
+        commands(dispatcher.handle(&ev))
+    }
+
+    /// Press and release the key `name`, and return the commands run
+    /// on the press
+    fn tap(dispatcher: &mut Dispatcher, name: &str) -> Vec<String> {
+        let found = send(dispatcher, name, KeyState::Pressed, 0.0);
+        assert!(send(dispatcher, name, KeyState::Released, 0.0).is_empty());
+        found
+    }
+
+    #[test]
+    fn deferred_prefix() {
+        let text = "KEY_ESC => \"esc\"\nKEY_ESC, KEY_KP1 => \"esc 1\"\nKEY_KP2 => \"two\"\n";
+
+        // the longer sequence follows
+        let mut d = dispatcher("prefix1", text, 2.0);
+        assert!(tap(&mut d, "KEY_ESC").is_empty());
+        assert!(d.deadline().is_some());
+        assert_eq!(tap(&mut d, "KEY_KP1"), ["esc 1"]);
+
+        // a key that does not continue it
+        assert!(tap(&mut d, "KEY_ESC").is_empty());
+        assert_eq!(tap(&mut d, "KEY_KP2"), ["esc", "two"]);
+
+        // no key before the delay expires
+        let mut d = dispatcher("prefix2", text, 0.0);
+        assert!(tap(&mut d, "KEY_ESC").is_empty());
+        assert_eq!(commands(d.expire()), ["esc"]);
+        assert!(d.deadline().is_none());
+    }
+
+    #[test]
+    fn on_timeout() {
+        let text = "on_timeout = \"aborted\"\nKEY_KP1, KEY_KP2 => \"x\"\n";
+        let mut d = dispatcher("timeout", text, 0.0);
+        assert!(tap(&mut d, "KEY_KP1").is_empty());
+        assert_eq!(commands(d.expire()), ["aborted"]);
+        assert!(commands(d.expire()).is_empty());
+    }
+
+    #[test]
+    fn chord() {
+        let text = "KEY_KP1+KEY_KP2 => \"chord\"\nKEY_KP1 => \"one\"\n";
+        let mut d = dispatcher("chord", text, 2.0);
+        for (first, second) in [("KEY_KP1", "KEY_KP2"), ("KEY_KP2", "KEY_KP1")] {
+            assert!(send(&mut d, first, KeyState::Pressed, 0.0).is_empty());
+            assert_eq!(send(&mut d, second, KeyState::Pressed, 0.0), ["chord"]);
+            assert!(send(&mut d, first, KeyState::Released, 0.0).is_empty());
+            assert!(send(&mut d, second, KeyState::Released, 0.0).is_empty());
+        }
+
+        // the chord is not completed: the key counts on its own
+        assert!(send(&mut d, "KEY_KP1", KeyState::Pressed, 0.0).is_empty());
+        assert_eq!(send(&mut d, "KEY_KP1", KeyState::Released, 0.0), ["one"]);
+    }
+
+    #[test]
+    fn long_press() {
+        let text = "long_press = 0.5\nKEY_KP1 => \"tap\"\nKEY_KP1:long => \"hold\"\n";
+        let mut d = dispatcher("long", text, 2.0);
+        assert!(send(&mut d, "KEY_KP1", KeyState::Pressed, 10.0).is_empty());
+        assert_eq!(send(&mut d, "KEY_KP1", KeyState::Released, 10.2), ["tap"]);
+        assert!(send(&mut d, "KEY_KP1", KeyState::Pressed, 11.0).is_empty());
+        assert_eq!(send(&mut d, "KEY_KP1", KeyState::Released, 11.6), ["hold"]);
+    }
+
+    #[test]
+    fn repeat() {
+        let text = "KEY_UP => \"up\" [repeat]\nKEY_DOWN => \"down\"\n";
+        let mut d = dispatcher("repeat", text, 2.0);
+        assert_eq!(send(&mut d, "KEY_UP", KeyState::Pressed, 0.0), ["up"]);
+        assert_eq!(send(&mut d, "KEY_UP", KeyState::Repeated, 0.5), ["up"]);
+        assert_eq!(send(&mut d, "KEY_UP", KeyState::Repeated, 0.6), ["up"]);
+        assert!(send(&mut d, "KEY_UP", KeyState::Released, 0.7).is_empty());
+        assert!(send(&mut d, "KEY_UP", KeyState::Repeated, 0.8).is_empty());
+
+        // bindings without the option do not repeat
+        assert_eq!(send(&mut d, "KEY_DOWN", KeyState::Pressed, 1.0), ["down"]);
+        assert!(send(&mut d, "KEY_DOWN", KeyState::Repeated, 1.5).is_empty());
+    }
+
+    #[test]
+    fn modes() {
+        let text = concat!(
+            "KEY_KP0 => mode(\"once\")\n",
+            "KEY_KPDOT => mode(\"timed\")\n",
+            "KEY_KP1 => \"default\"\n",
+            "[mode once]\n",
+            "oneshot = 1\n",
+            "KEY_KP1 => \"once\"\n",
+            "[mode timed]\n",
+            "timeout = 0.1\n",
+            "KEY_KP1 => \"timed\"\n",
+        );
+        let mut d = dispatcher("modes", text, 2.0);
+
+        // a one-shot mode is left after one binding
+        assert!(tap(&mut d, "KEY_KP0").is_empty());
+        assert_eq!(tap(&mut d, "KEY_KP1"), ["once"]);
+        assert_eq!(tap(&mut d, "KEY_KP1"), ["default"]);
+
+        // a mode with a timeout stays until it expires
+        assert!(tap(&mut d, "KEY_KPDOT").is_empty());
+        assert_eq!(tap(&mut d, "KEY_KP1"), ["timed"]);
+        assert_eq!(tap(&mut d, "KEY_KP1"), ["timed"]);
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(d.expire().is_empty());
+        assert_eq!(tap(&mut d, "KEY_KP1"), ["default"]);
+    }
+}
@@ -0,0 +1,108 @@
+//! Native discovery of input devices
+//!
+//! Enumerates the /dev/input/event* devices by parsing
+//! /proc/bus/input/devices, so that no X server (and no `xinput`) is
+//! required. The records returned are the same as those produced by
+//! the xinput interface, so the device filters work with both.
+
+use std::fs;
+use regex::Regex;
+use std::sync::OnceLock;
+use anyhow::{Result, Context};
+
+use crate::key_codes::EventType;
+use crate::xinput::XinputEntry;
+
+/// File listing all input devices known to the kernel
+const PROC_INPUT_DEVICES: &str = "/proc/bus/input/devices";
+
+/// Parse one block of /proc/bus/input/devices
+///
+/// A block looks like this:
+///
+/// ```text
+/// I: Bus=0003 Vendor=046a Product=0014 Version=0110
+/// N: Name="HID 046a:0014"
+/// P: Phys=usb-0000:00:14.0-2/input0
+/// S: Sysfs=/devices/pci0000:00/0000:00:14.0/usb1/1-2/1-2:1.0/0003:046A:0014.0001/input/input17
+/// U: Uniq=
+/// H: Handlers=sysrq kbd event17 leds
+/// B: PROP=0
+/// B: EV=120013
+/// ```
+///
+/// Returns None if the block does not belong to an event device.
+///
+fn entry_from_block(block: &str) -> Option<XinputEntry> {
+    static RX1: OnceLock<Regex> = OnceLock::new();
+    let rx1 = RX1.get_or_init(
+        || Regex::new(r"Vendor=(?<vid>[0-9a-fA-F]+)\s+Product=(?<pid>[0-9a-fA-F]+)").unwrap()
+    );
+
+    static RX2: OnceLock<Regex> = OnceLock::new();
+    let rx2 = RX2.get_or_init(
+        || Regex::new(r"^(?<tag>[A-Z]):\s*(?<key>[A-Za-z]+)=(?<val>.*)$").unwrap()
+    );
+
+    let mut entry = XinputEntry {
+        name: "".to_string(),
+        id: 0,
+        keyboard: false,
+        pointer: false,
+        slave: true,
+        floating: false,
+        attached_to: None,
+        device: "".to_string(),
+        usb_vid: 0,
+        usb_pid: 0,
+    };
+    let mut has_kbd_handler = false;
+    let mut has_key_events = false;
+
+    for line in block.lines() {
+        if let Some(caps) = rx1.captures(line) {
+            entry.usb_vid = u16::from_str_radix(&caps["vid"], 16).unwrap_or(0);
+            entry.usb_pid = u16::from_str_radix(&caps["pid"], 16).unwrap_or(0);
+        } else if let Some(caps) = rx2.captures(line) {
+            let val = caps["val"].trim();
+            match (&caps["tag"], &caps["key"]) {
+                ("N", "Name") => entry.name = val.trim_matches('"').to_string(),
+                ("H", "Handlers") => {
+                    for handler in val.split_whitespace() {
+                        if handler.starts_with("event") {
+                            entry.device = format!("/dev/input/{}", handler);
+                        } else if handler == "kbd" {
+                            has_kbd_handler = true;
+                        } else if handler.starts_with("mouse") {
+                            entry.pointer = true;
+                        }
+                    }
+                },
+                ("B", "EV") => {
+                    if let Ok(bits) = u64::from_str_radix(val, 16) {
+                        has_key_events = bits & (1 << EventType::EvKey as u16) != 0;
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+    entry.keyboard = has_kbd_handler && has_key_events;
+
+    if entry.device.is_empty() {
+        None
+    } else {
+        Some(entry)
+    }
+}
+
+/// collect infos about all event devices listed by the kernel
+///
+pub fn read_evdev() -> Result<Vec<XinputEntry>> {
+    let text = fs::read_to_string(PROC_INPUT_DEVICES)
+        .context(format!("reading {}", PROC_INPUT_DEVICES))?;
+    Ok(text
+       .split("\n\n")
+       .filter_map(entry_from_block)
+       .collect())
+}
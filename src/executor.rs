@@ -0,0 +1,489 @@
+//! Asynchronous execution of the commands bound to key sequences
+//!
+//! Commands are spawned as child processes, so that the event loop
+//! keeps processing key strokes while they run. Each child is
+//! watched by a small task that reaps it when it terminates, collects
+//! its output, and reports the result. Failed commands trigger the
+//! `on_error` hook, if one is configured.
+
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::{Child, Command};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout, Instant};
+use anyhow::{Result, Context, bail};
+
+use crate::action::Action;
+use crate::config::KeyValueStore;
+use crate::key_tree::{Binding, RunMode};
+use crate::uinput::VirtualKeyboard;
+
+/// Time given to applications to notice the new virtual keyboard
+/// before it sends its first key strokes
+const KEYBOARD_SETTLE: Duration = Duration::from_millis(200);
+
+/// What has been read from a child's stdout or stderr
+#[derive(Default)]
+struct Output {
+    /// the first OUTPUT_LIMIT bytes
+    kept: Vec<u8>,
+    /// number of bytes read, including those discarded
+    total: usize,
+}
+
+/// Read a child's stdout or stderr until it is closed, keeping only
+/// the first OUTPUT_LIMIT bytes
+fn collect_output<R: AsyncRead + Unpin + Send + 'static>(pipe: Option<R>) -> JoinHandle<Output> {
+    tokio::spawn(async move {
+        let mut output = Output::default();
+        if let Some(mut pipe) = pipe {
+            let mut chunk = [0u8; 4096];
+            while let Ok(n) = pipe.read(&mut chunk).await {
+                if n == 0 {
+                    break;
+                }
+                let room = OUTPUT_LIMIT.saturating_sub(output.kept.len());
+                output.kept.extend_from_slice(&chunk[..n.min(room)]);
+                output.total += n;
+            }
+        }
+        output
+    })
+}
+
+/// Time to wait for the output of a command after it has terminated
+/// (processes it started in the background may keep the pipes open)
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Wait for the output collected by `collect_output`, but not longer
+/// than OUTPUT_GRACE
+///
+/// After that, the pipes are still read and their content discarded
+/// until they are closed, so that processes started in the background
+/// do not get SIGPIPE when they write to them.
+///
+async fn finish_output(handle: JoinHandle<Output>) -> Output {
+    match timeout(OUTPUT_GRACE, handle).await {
+        Ok(output) => output.unwrap_or_default(),
+        Err(_) => Output::default(),
+    }
+}
+
+/// Terminate a child: send SIGTERM to its process group, and SIGKILL
+/// if it is still running after `grace`
+async fn terminate(child: &mut Child, grace: Duration) -> std::io::Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) };
+        if let Ok(status) = timeout(grace, child.wait()).await {
+            return status;
+        }
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+    }
+    let _ = child.start_kill();
+    child.wait().await
+}
+
+/// Maximum number of bytes of stdout and stderr kept in an ExecResult
+const OUTPUT_LIMIT: usize = 2000;
+
+/// Turn captured output into text, keeping at most OUTPUT_LIMIT bytes
+fn truncate_output(output: &Output) -> String {
+    let text = String::from_utf8_lossy(&output.kept);
+    let text = text.trim_end();
+    if output.total <= OUTPUT_LIMIT && text.len() <= OUTPUT_LIMIT {
+        return text.to_string();
+    }
+    let mut end = OUTPUT_LIMIT.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes truncated)", &text[..end], output.total.max(text.len()) - end)
+}
+
+/// Outcome of running a command
+#[derive(Debug, Clone)]
+pub struct ExecResult {
+    pub command: String,
+    /// exit code, if the command terminated normally
+    pub code: Option<i32>,
+    /// signal that terminated the command, if any
+    pub signal: Option<i32>,
+    /// reason why the command could not be run at all
+    pub error: Option<String>,
+    /// the command was killed because it ran longer than its timeout
+    pub timed_out: bool,
+    pub duration: Duration,
+    /// (truncated) output of the command
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExecResult {
+    /// Result of a command that terminated with `status`
+    fn from_status(command: &str, status: ExitStatus, started: Instant,
+                   stdout: &Output, stderr: &Output) -> Self {
+        Self {
+            command: command.to_string(),
+            code: status.code(),
+            signal: status.signal(),
+            error: None,
+            timed_out: false,
+            duration: started.elapsed(),
+            stdout: truncate_output(stdout),
+            stderr: truncate_output(stderr),
+        }
+    }
+
+    /// Result of a command that could not be run
+    fn from_error(command: &str, error: &anyhow::Error) -> Self {
+        Self {
+            command: command.to_string(),
+            code: None,
+            signal: None,
+            error: Some(format!("{:#}", error)),
+            timed_out: false,
+            duration: Duration::ZERO,
+            stdout: "".to_string(),
+            stderr: "".to_string(),
+        }
+    }
+
+    /// True if the command ran and exited with code 0 before its
+    /// timeout
+    pub fn success(&self) -> bool {
+        self.code == Some(0) && !self.timed_out
+    }
+
+    /// Short description of how the command terminated, prefixed with
+    /// "timed out" if it was terminated because of its timeout
+    pub fn status(&self) -> String {
+        if let Some(error) = &self.error {
+            return error.clone();
+        }
+        let status = if let Some(signal) = self.signal {
+            format!("killed by signal {}", signal)
+        } else if let Some(code) = self.code {
+            format!("exit code {}", code)
+        } else {
+            "unknown status".to_string()
+        };
+        if self.timed_out {
+            format!("timed out, {}", status)
+        } else {
+            status
+        }
+    }
+
+    /// Report the result on stderr
+    ///
+    /// Failures are always reported, successful commands only with
+    /// verbosity >= 1. The output of the command is shown with
+    /// verbosity >= 2, or with verbosity >= 1 if the command failed.
+    ///
+    pub fn log(&self, verbosity: u8) {
+        let success = self.success();
+        if success && verbosity < 1 {
+            return;
+        }
+        eprintln!("{} <{}>: {} after {:.3}s",
+                  if success { "Finished" } else { "Failed" },
+                  self.command,
+                  self.status(),
+                  self.duration.as_secs_f32());
+        if verbosity >= 2 || (!success && verbosity >= 1) {
+            if !self.stdout.is_empty() {
+                eprintln!("  stdout: {}", self.stdout);
+            }
+            if !self.stderr.is_empty() {
+                eprintln!("  stderr: {}", self.stderr);
+            }
+        }
+    }
+}
+
+/// Settings shared by the executor and its watcher tasks
+#[derive(Clone)]
+struct Reporting {
+    shell: String,
+    on_error: Option<String>,
+    verbosity: u8,
+    /// timeout of commands without a `timeout` option
+    default_timeout: Option<Duration>,
+    /// time between SIGTERM and SIGKILL
+    kill_grace: Duration,
+}
+
+impl Reporting {
+    /// Log the result and run the `on_error` hook if the command
+    /// failed
+    ///
+    /// The hook is run by the shell; the failed command, its status
+    /// and its stderr are passed in the environment variables
+    /// KEYBUDDY_FAILED_COMMAND, KEYBUDDY_FAILED_STATUS and
+    /// KEYBUDDY_FAILED_STDERR.
+    ///
+    async fn report(&self, result: &ExecResult) {
+        result.log(self.verbosity);
+        if result.success() {
+            return;
+        }
+        let Some(on_error) = &self.on_error else {
+            return;
+        };
+        let status = Command::new(&self.shell)
+            .arg("-c")
+            .arg(on_error)
+            .env("KEYBUDDY_FAILED_COMMAND", &result.command)
+            .env("KEYBUDDY_FAILED_STATUS", result.status())
+            .env("KEYBUDDY_FAILED_STDERR", &result.stderr)
+            .stdin(Stdio::null())
+            .status()
+            .await;
+        if !matches!(status, Ok(s) if s.success()) {
+            eprintln!("on_error hook <{}> failed", on_error);
+        }
+    }
+}
+
+/// Identity of a command started with `restart`: the file and line
+/// where its binding was defined, and its position among the
+/// binding's actions
+///
+/// Placeholders do not matter, so `mpv station{1}` started by `/1`
+/// is killed when `/2` fires the same binding.
+///
+type RestartKey = (Option<(Arc<str>, usize)>, usize);
+
+/// The RestartKey of `cmd`, one of the commands of `binding`
+fn restart_key(cmd: &str, binding: &Binding) -> RestartKey {
+    let index = binding.action
+        .items()
+        .iter()
+        .position(|action| matches!(action, Action::Command(c) if c == cmd))
+        .unwrap_or(0);
+    (binding.origin.clone(), index)
+}
+
+/// Spawns commands and keeps track of the ones still running
+pub struct Executor {
+    reporting: Reporting,
+    /// kill switches of running commands started with `restart`,
+    /// by binding
+    running: HashMap<RestartKey, oneshot::Sender<()>>,
+    /// virtual keyboard for `type` and `key` actions, created when
+    /// first needed
+    keyboard: Option<VirtualKeyboard>,
+    /// true once creating the virtual keyboard has failed
+    no_keyboard: bool,
+}
+
+impl Executor {
+    /// Create an executor with the settings in `kv` (see
+    /// `configure`) that logs results according to `verbosity`
+    pub fn new(kv: &KeyValueStore, verbosity: u8) -> Self {
+        let mut executor = Self {
+            reporting: Reporting {
+                shell: "sh".to_string(),
+                on_error: None,
+                verbosity,
+                default_timeout: None,
+                kill_grace: Duration::ZERO,
+            },
+            running: HashMap::new(),
+            keyboard: None,
+            no_keyboard: false,
+        };
+        executor.configure(kv);
+        executor
+    }
+
+    /// Take the settings from `kv`
+    ///
+    /// Commands are run with `shell` (default sh); `on_error` is a
+    /// shell command that is run whenever a command fails.
+    ///
+    /// Commands running longer than their `timeout` option, or
+    /// `command_timeout` if they have none, get SIGTERM and, if they
+    /// are still running `kill_grace` seconds (default 2) later,
+    /// SIGKILL.
+    ///
+    pub fn configure(&mut self, kv: &KeyValueStore) {
+        let reporting = &mut self.reporting;
+        reporting.shell = kv.get_str("shell").unwrap_or("sh").to_string();
+        reporting.on_error = kv.get_str("on_error").map(|x| x.to_string());
+        reporting.default_timeout = kv.get_float("command_timeout").map(Duration::from_secs_f32);
+        reporting.kill_grace = Duration::from_secs_f32(kv.get_float("kill_grace").unwrap_or(2.0));
+    }
+
+    /// Build the process for `cmd`
+    ///
+    /// The command is run by the shell (as `shell -c cmd`), unless the
+    /// binding has the `exec` option, in which case it is split into
+    /// words and executed without a shell.
+    ///
+    fn build_command(&self, cmd: &str, binding: &Binding) -> Result<Command> {
+        let mut command = if binding.exec {
+            let parts = shlex::split(cmd)
+                .context(format!("Could not split command <{}>", cmd))?;
+            let Some((cmd, args)) = parts.split_first() else {
+                bail!("Empty command");
+            };
+            let mut command = Command::new(cmd);
+            command.args(args);
+            command
+        } else {
+            let mut command = Command::new(&self.reporting.shell);
+            command.arg("-c").arg(cmd);
+            command
+        };
+        // own process group, so that killing it also kills its children
+        command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0);
+        Ok(command)
+    }
+
+    /// Start `cmd` with the options of `binding`
+    ///
+    /// Depending on the binding's run mode, this returns as soon as
+    /// the command has been started (`detach`), waits until it has
+    /// terminated (`wait`), or first kills the instance started by a
+    /// previous invocation of the binding if it is still running
+    /// (`restart`).
+    ///
+    /// The variables in `env` are added to the command's environment.
+    /// The result is reported when the command terminates. Commands
+    /// that exceed their timeout are terminated.
+    ///
+    pub async fn run(&mut self, cmd: &str, binding: &Binding, env: &[(&str, String)]) {
+        self.running.retain(|_, kill_tx| !kill_tx.is_closed());
+        if binding.mode == RunMode::Restart {
+            if let Some(kill_tx) = self.running.remove(&restart_key(cmd, binding)) {
+                let _ = kill_tx.send(());
+            }
+        }
+
+        let child = self.build_command(cmd, binding)
+            .and_then(|mut command| command
+                      .envs(env.iter().map(|(k, v)| (k, v)))
+                      .spawn()
+                      .context(format!("Could not start <{}>", cmd)));
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                self.reporting.report(&ExecResult::from_error(cmd, &err)).await;
+                return;
+            }
+        };
+        let started = Instant::now();
+        let stdout = collect_output(child.stdout.take());
+        let stderr = collect_output(child.stderr.take());
+        let (kill_tx, kill_rx) = oneshot::channel::<()>();
+        let reporting = self.reporting.clone();
+        let command = cmd.to_string();
+        let deadline = binding.timeout
+            .or(reporting.default_timeout)
+            .filter(|t| !t.is_zero())
+            .and_then(|t| started.checked_add(t));
+
+        // the watcher reaps the child when it terminates, and kills it
+        // when it times out or is restarted
+        let watcher = tokio::spawn(async move {
+            let mut timed_out = false;
+            let status = tokio::select! {
+                status = child.wait() => status,
+                Ok(()) = kill_rx => terminate(&mut child, reporting.kill_grace).await,
+                _ = sleep_until(deadline.unwrap_or(started)), if deadline.is_some() => {
+                    timed_out = true;
+                    terminate(&mut child, reporting.kill_grace).await
+                },
+            };
+            let stdout = finish_output(stdout).await;
+            let stderr = finish_output(stderr).await;
+            let mut result = match status {
+                Ok(status) => ExecResult::from_status(&command, status, started, &stdout, &stderr),
+                Err(err) => ExecResult::from_error(&command, &err.into()),
+            };
+            result.timed_out = timed_out;
+            reporting.report(&result).await;
+        });
+
+        match binding.mode {
+            RunMode::Wait => {
+                let _ = watcher.await;
+            },
+            RunMode::Restart => {
+                self.running.insert(restart_key(cmd, binding), kill_tx);
+            },
+            RunMode::Detach => {},
+        }
+    }
+
+    /// The virtual keyboard, created on first use
+    ///
+    /// If it cannot be created (usually for lack of write access to
+    /// /dev/uinput), this is reported once and `None` is returned from
+    /// then on.
+    ///
+    async fn keyboard(&mut self) -> Option<&mut VirtualKeyboard> {
+        if self.keyboard.is_none() && !self.no_keyboard {
+            match VirtualKeyboard::new() {
+                Ok(keyboard) => {
+                    self.keyboard = Some(keyboard);
+                    sleep(KEYBOARD_SETTLE).await;
+                },
+                Err(err) => {
+                    eprintln!("No virtual keyboard: {:#}", err);
+                    self.no_keyboard = true;
+                },
+            }
+        }
+        self.keyboard.as_mut()
+    }
+
+    /// Type `text` into the focused window
+    ///
+    /// This uses the virtual keyboard, or `xdotool` if there is none,
+    /// and waits until the text has been typed.
+    ///
+    pub async fn type_text(&mut self, text: &str, env: &[(&str, String)]) {
+        if let Some(keyboard) = self.keyboard().await {
+            if let Err(err) = keyboard.type_text(text) {
+                eprintln!("{:#}", err);
+            }
+            return;
+        }
+        let Ok(text) = shlex::try_quote(text) else {
+            eprintln!("Cannot type text containing NUL characters");
+            return;
+        };
+        let cmd = format!("xdotool type --clearmodifiers -- {}", text);
+        let binding = Binding {
+            exec: true,
+            mode: RunMode::Wait,
+            ..Binding::new(Action::Command(cmd.clone()))
+        };
+        self.run(&cmd, &binding, env).await;
+    }
+
+    /// Send key combinations through the virtual keyboard
+    pub async fn send_keys(&mut self, combos: &[Vec<u16>]) {
+        let Some(keyboard) = self.keyboard().await else {
+            eprintln!("Cannot send keys without a virtual keyboard");
+            return;
+        };
+        for keys in combos {
+            if let Err(err) = keyboard.press_combo(keys) {
+                eprintln!("{:#}", err);
+                return;
+            }
+        }
+    }
+}
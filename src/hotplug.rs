@@ -0,0 +1,201 @@
+//! Supervisor that keeps a keypad connected across unplug/replug
+//!
+//! The supervisor waits until a device matching the configured
+//! filters shows up, sets it up (float or grab), and runs the key
+//! reader on it. When the device disappears, it goes back to
+//! waiting. Key strokes of all incarnations of the device are fed
+//! into the same channel, so the state of the dispatcher survives.
+
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+use anyhow::{Result, Context};
+
+use crate::config::{DeviceConfig, KeyValueData};
+use crate::inotify::{Inotify, InotifyEvent, IN_ATTRIB, IN_CREATE};
+use crate::key_reader::{key_device_setup, key_reader_task, DevFilter, Discovery, Isolation, KeyEvent, NoMatchingDevice,
+                        ReaderExit};
+
+/// Directory in which the kernel (or udev) creates event devices
+const INPUT_DIR: &str = "/dev/input";
+
+/// Retry interval when no inotify event arrives, e.g. because X only
+/// lists a device some time after its node was created
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything needed to find and set up a device
+pub struct DeviceSpec {
+    /// name of the `[device]` block, used in messages
+    pub name: String,
+    /// index of the device, used to tag its events
+    pub source: usize,
+    pub discovery: Discovery,
+    pub isolation: Isolation,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub dev_filter: Option<DevFilter>,
+}
+
+/// The settings that `DeviceSpec::from_config` reads
+const DEVICE_SETTINGS: &[&str] = &["vid", "pid", "device_include", "device_exclude", "discovery", "isolation"];
+
+/// True if `new` has the same `[device]` blocks as `old`, with the
+/// same filters and setup options
+///
+/// Only then can the supervisors of `old` serve a config reloaded as
+/// `new`.
+///
+pub fn same_devices(old: &[DeviceConfig], new: &[DeviceConfig]) -> bool {
+    old.len() == new.len()
+        && old.iter().zip(new).all(|(old, new)| {
+            old.name == new.name
+                && DEVICE_SETTINGS.iter().all(|key| old.kv.get(key) == new.kv.get(key))
+        })
+}
+
+impl DeviceSpec {
+    /// Collect the filters and setup options of a `[device]` block
+    ///
+    /// `source` is the index used to tag the device's events.
+    ///
+    pub fn from_config(source: usize, dev: &DeviceConfig) -> Result<Self> {
+        let kv = &dev.kv;
+        let discovery = match kv.get_str("discovery") {
+            Some(name) => Discovery::from_name(name)?,
+            None => Discovery::Auto,
+        };
+        let isolation = match kv.get_str("isolation") {
+            Some(name) => Isolation::from_name(name)?,
+            None => Isolation::Float,
+        };
+        let mut vid = None;
+        if let Some(KeyValueData::Int(v)) = kv.get("vid") {
+            vid = Some(v as u16);
+        }
+        let mut pid = None;
+        if let Some(KeyValueData::Int(v)) = kv.get("pid") {
+            pid = Some(v as u16);
+        }
+        let mut f1 : DevFilter = Box::new(|_x: &str| -> bool { true });
+        let mut f2 : DevFilter = Box::new(|_x: &str| -> bool { true });
+        if let Some(KeyValueData::Text(v)) = kv.get("device_include") {
+            f1 = Box::new(move |x: &str| -> bool { x.contains(v.as_str()) });
+        }
+        if let Some(KeyValueData::Text(v)) = kv.get("device_exclude") {
+            f2 = Box::new(move |x: &str| -> bool { !x.contains(v.as_str()) });
+        }
+        Ok(Self {
+            name: dev.name.clone(),
+            source,
+            discovery,
+            isolation,
+            vid,
+            pid,
+            dev_filter: Some(Box::new(move |x| { f1(x) & f2(x) })),
+        })
+    }
+}
+
+/// True if `ev` may mean that a device can now be found: a new entry
+/// in /dev/input, or an event node whose permissions have changed
+/// (e.g. set by udev after creating it)
+fn is_device_change(ev: &InotifyEvent) -> bool {
+    ev.mask & IN_CREATE != 0 || (ev.mask & IN_ATTRIB != 0 && ev.name.starts_with("event"))
+}
+
+/// Wait for a change in /dev/input, for the retry interval to
+/// elapse, or for a stop request.
+///
+/// Returns false if a stop request was received.
+///
+async fn wait_for_change(inotify: &Option<Inotify>, stop_rx: &mut mpsc::Receiver<()>) -> bool {
+    let changed = async {
+        if let Some(inotify) = inotify {
+            while let Ok(events) = inotify.read_events().await {
+                if events.iter().any(is_device_change) {
+                    return;
+                }
+            }
+        }
+        std::future::pending::<()>().await
+    };
+    tokio::select! {
+        _ = changed => true,
+        _ = sleep(RETRY_INTERVAL) => true,
+        _ = stop_rx.recv() => false,
+    }
+}
+
+/// Task that finds the device described by `spec`, reads key strokes
+/// from it and feeds these into ev_tx, and repeats this whenever the
+/// device is unplugged and reconnected. It terminates when anything
+/// is received via stop_rx.
+///
+/// Returns an error if the device cannot be set up for another reason
+/// than not being connected, e.g. because several devices match.
+///
+pub async fn device_supervisor(spec: DeviceSpec,
+                               ev_tx: mpsc::Sender<KeyEvent>,
+                               mut stop_rx: mpsc::Receiver<()>)
+                               -> Result<()>
+{
+    let inotify = match Inotify::new() {
+        Ok(inotify) => match inotify.add_watch(Path::new(INPUT_DIR), IN_CREATE | IN_ATTRIB) {
+            Ok(_) => Some(inotify),
+            Err(err) => {
+                eprintln!("{}: {}, polling for devices instead", spec.name, err);
+                None
+            },
+        },
+        Err(err) => {
+            eprintln!("{}: {}, polling for devices instead", spec.name, err);
+            None
+        },
+    };
+
+    let mut waiting = false;
+    loop {
+        let key_device = key_device_setup(spec.discovery,
+                                          spec.isolation,
+                                          spec.vid,
+                                          spec.pid,
+                                          spec.dev_filter.as_ref());
+        let mut key_device = match key_device {
+            Ok(key_device) => key_device,
+            Err(err) if err.is::<NoMatchingDevice>() => {
+                if !waiting {
+                    eprintln!("{}: {}", spec.name, err);
+                    eprintln!("{}: waiting for device to be connected ...", spec.name);
+                    waiting = true;
+                }
+                if !wait_for_change(&inotify, &mut stop_rx).await {
+                    return Ok(());
+                }
+                continue;
+            },
+            Err(err) => return Err(err).context(spec.name.clone()),
+        };
+        waiting = false;
+
+        let exit = key_reader_task(&key_device.device,
+                                   spec.source,
+                                   spec.isolation == Isolation::Grab,
+                                   ev_tx.clone(),
+                                   &mut stop_rx).await;
+        if exit == ReaderExit::Stopped {
+            key_device.restore();
+            return Ok(());
+        }
+        if !Path::new(&key_device.device).exists() {
+            key_device.forget();
+        }
+        key_device.restore();
+        eprintln!("{}: lost device {}", spec.name, key_device.device);
+
+        // do not spin if the device is present but cannot be read
+        if !wait_for_change(&inotify, &mut stop_rx).await {
+            return Ok(());
+        }
+    }
+}
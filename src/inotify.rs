@@ -0,0 +1,97 @@
+//! Minimal asynchronous interface to the kernel's inotify API
+//!
+//! Only what is needed to watch a few directories: create an
+//! instance, add watches, and wait for events.
+
+use std::ffi::CString;
+use std::io::Cursor;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use byteorder::{NativeEndian, ReadBytesExt};
+use tokio::io::unix::AsyncFd;
+use anyhow::{Result, Context, bail};
+
+pub use libc::{IN_CREATE, IN_ATTRIB, IN_CLOSE_WRITE, IN_DELETE, IN_MOVED_TO};
+
+/// One event reported by inotify
+#[derive(Debug, Clone)]
+pub struct InotifyEvent {
+    /// watch descriptor returned by `add_watch`
+    pub wd: i32,
+    /// IN_* flags describing the event
+    pub mask: u32,
+    /// name of the file within the watched directory (may be empty)
+    pub name: String,
+}
+
+/// An inotify instance registered with the tokio reactor
+pub struct Inotify {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl Inotify {
+    /// Create a new inotify instance without any watches
+    pub fn new() -> Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            bail!("inotify_init1 failed: {}", std::io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // the OwnedFd is valid and is only closed when AsyncFd is dropped
+        let fd = unsafe { AsyncFd::register(fd) }.context("registering inotify instance")?;
+        Ok(Self { fd })
+    }
+
+    /// Watch `path` for the events in `mask` and return the watch
+    /// descriptor
+    pub fn add_watch(&self, path: &Path, mask: u32) -> Result<i32> {
+        let cpath = CString::new(path.as_os_str().as_bytes())
+            .context("converting path for inotify")?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), cpath.as_ptr(), mask) };
+        if wd < 0 {
+            bail!("Could not watch {}: {}", path.display(), std::io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    /// Wait until at least one event is available and return all
+    /// events read
+    pub async fn read_events(&self) -> Result<Vec<InotifyEvent>> {
+        let mut buffer = [0u8; 4096];
+        let len = loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| {
+                let n = unsafe {
+                    libc::read(fd.as_raw_fd(), buffer.as_mut_ptr() as *mut libc::c_void, buffer.len())
+                };
+                if n < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                Ok(result) => break result.context("reading inotify events")?,
+                Err(_would_block) => continue,
+            }
+        };
+
+        // each record is: int wd; uint32 mask; uint32 cookie; uint32 len; char name[len]
+        let mut events = vec![];
+        let mut rdr = Cursor::new(&buffer[..len]);
+        while (rdr.position() as usize) + 16 <= len {
+            let wd     = rdr.read_i32::<NativeEndian>()?;
+            let mask   = rdr.read_u32::<NativeEndian>()?;
+            let _cookie = rdr.read_u32::<NativeEndian>()?;
+            let nlen   = rdr.read_u32::<NativeEndian>()? as usize;
+            let start  = rdr.position() as usize;
+            let name = &buffer[start..(start + nlen).min(len)];
+            let name = String::from_utf8_lossy(name)
+                .trim_end_matches('\0')
+                .to_string();
+            rdr.set_position((start + nlen) as u64);
+            events.push(InotifyEvent { wd, mask, name });
+        }
+        Ok(events)
+    }
+}
@@ -31,7 +31,7 @@ impl RawCodes {
 /// Important are mostly EvKey, EvLev, EvMsc, and EvSyn.
 #[repr(u16)]
 #[derive(Clone, Copy)]
-#[allow(dead_code)]
+#[allow(dead_code, clippy::enum_variant_names)]
 pub enum EventType {
     EvSyn      = 0x00,
     EvKey      = 0x01,
@@ -60,8 +60,8 @@ pub fn code_from_key_name(name: &str) -> Option<u16> {
     let hash = STORE.get_or_init(
         || {
             let mut hm = HashMap::new();
-            for (ref name, code) in KEYCODES {
-                hm.insert(*name, code);
+            for (name, code) in KEYCODES {
+                hm.insert(name, code);
             }
             hm
         }
@@ -69,14 +69,20 @@ pub fn code_from_key_name(name: &str) -> Option<u16> {
     hash.get(name).copied()
 }
 
+/// All key codes with a name, e.g. to announce the keys of a virtual
+/// keyboard
+pub fn all_key_codes() -> impl Iterator<Item = u16> {
+    KEYCODES.iter().map(|(_, code)| *code)
+}
+
 /// Get a key name given the key code
 pub fn key_name_from_code(code: u16) -> Option<&'static str> {
     static STORE: OnceLock<HashMap<u16, &str>> = OnceLock::new();
     let hash = STORE.get_or_init(
         || {
             let mut hm = HashMap::<u16, &str>::new();
-            for (ref name, code) in KEYCODES {
-                hm.insert(code, *name);
+            for (name, code) in KEYCODES {
+                hm.insert(code, name);
             }
             hm
         }
@@ -1,100 +1,336 @@
 //! Sets up thread that gets keystrokes from keypad
 
+use std::fmt;
 use std::process::Command;
+use std::os::fd::{AsRawFd, RawFd};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::sync::mpsc;
 
 use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
 use byteorder::{NativeEndian, ReadBytesExt};
 use anyhow::{Result, Context, bail};
 
 use crate::key_codes::EventType;
 use crate::xinput::read_xinput;
+use crate::evdev::read_evdev;
+use crate::uinput::VIRTUAL_KEYBOARD_NAME;
+
+/// Predicate applied to the device name
+pub type DevFilter = Box<dyn Fn(&str)->bool + Send + Sync>;
+
+/// Backend used to enumerate input devices
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Discovery {
+    /// Use xinput when an X display is available and it lists devices
+    /// with an event node (XWayland does not), evdev otherwise
+    Auto,
+    /// Ask `xinput` (requires a running X server)
+    Xinput,
+    /// Read the kernel's list of event devices directly
+    Evdev,
+}
+
+impl Discovery {
+    /// Parse the value of the `discovery` config key
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(Self::Auto),
+            "xinput" => Ok(Self::Xinput),
+            "evdev" => Ok(Self::Evdev),
+            _ => bail!("Unknown discovery backend '{}' (use auto, xinput, or evdev)", name),
+        }
+    }
+}
+
+/// How the keypad is kept from sending its keystrokes to the
+/// focused application
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Isolation {
+    /// Make the device "float" using `xinput` (X11 only)
+    Float,
+    /// Grab the event device exclusively (EVIOCGRAB)
+    Grab,
+    /// Leave the device alone
+    None,
+}
+
+impl Isolation {
+    /// Parse the value of the `isolation` config key
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "float" => Ok(Self::Float),
+            "grab" => Ok(Self::Grab),
+            "none" => Ok(Self::None),
+            _ => bail!("Unknown isolation method '{}' (use float, grab, or none)", name),
+        }
+    }
+}
+
+/// ioctl request taking/releasing an exclusive grab,
+/// _IOW('E', 0x90, int) in /usr/include/linux/input.h
+const EVIOCGRAB: libc::c_ulong = 0x40044590;
+
+/// Exclusive grab of an event device
+///
+/// While the grab is held, no other client (X server, Wayland
+/// compositor, console) receives events from the device. The grab is
+/// released when this guard is dropped.
+///
+struct Grab(RawFd);
+
+impl Grab {
+    fn new(fd: RawFd) -> Result<Self> {
+        if unsafe { libc::ioctl(fd, EVIOCGRAB, 1 as libc::c_int) } < 0 {
+            bail!("EVIOCGRAB failed: {}", std::io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl Drop for Grab {
+    fn drop(&mut self) {
+        unsafe { libc::ioctl(self.0, EVIOCGRAB, 0 as libc::c_int) };
+    }
+}
+
+/// Input device selected by `key_device_setup`
+///
+/// If the device was made floating, the master it was attached to is
+/// remembered, and the device is reattached to it by `restore()` or,
+/// at the latest, when the KeyDevice is dropped.
+///
+pub struct KeyDevice {
+    /// event device node, e.g. /dev/input/event17
+    pub device: String,
+    /// xinput ids of the floated device and its original master
+    reattach: Option<(usize, usize)>,
+}
+
+impl KeyDevice {
+    /// Reattach a floated device to its original master
+    ///
+    /// Calling this more than once is harmless.
+    ///
+    pub fn restore(&mut self) {
+        if let Some((id, master)) = self.reattach.take() {
+            let status = Command::new("xinput")
+                .arg("reattach")
+                .arg(id.to_string())
+                .arg(master.to_string())
+                .status();
+            if !matches!(status, Ok(s) if s.success()) {
+                eprintln!("Could not reattach device {} to master {}", id, master);
+            }
+        }
+    }
+
+    /// Forget about the original attachment, e.g. because the device
+    /// has been unplugged and its xinput id is no longer valid
+    pub fn forget(&mut self) {
+        self.reattach = None;
+    }
+}
+
+impl Drop for KeyDevice {
+    fn drop(&mut self) {
+        self.restore();
+    }
+}
+
+/// Error of `key_device_setup` when no device matches (yet)
+#[derive(Debug)]
+pub struct NoMatchingDevice;
+
+impl fmt::Display for NoMatchingDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "No matching input devices found !")
+    }
+}
+
+impl std::error::Error for NoMatchingDevice {}
 
 /// Find device based on USB id and a predicate based on the device's
 /// name (the one listed by e.g. lsusb), Make the device float, and
-/// return device name, or None is an error occurred
+/// return the device, or an error
+///
+/// The device is only made floating when `isolation` is
+/// `Isolation::Float`. Devices found by the evdev backend are not
+/// known to X and can therefore not be made floating.
 ///
-pub fn key_device_setup(vid: Option<u16>,
+pub fn key_device_setup(discovery: Discovery,
+                        isolation: Isolation,
+                        vid: Option<u16>,
                         pid: Option<u16>,
-                        dev_filter: Option<Box<dyn Fn(&str)->bool>>)
-                        -> Result<String>
+                        dev_filter: Option<&DevFilter>)
+                        -> Result<KeyDevice>
 {
     // get device list and filter it
-    let mut xdevs = read_xinput()
-        .context("getting devices listed by xinput")?;
+    let use_xinput = match discovery {
+        Discovery::Xinput => true,
+        Discovery::Evdev => false,
+        Discovery::Auto => std::env::var_os("DISPLAY").is_some(),
+    };
+    let mut xdevs = if use_xinput {
+        let mut xdevs = match read_xinput() {
+            Ok(xdevs) => xdevs,
+            Err(err) if discovery == Discovery::Auto => {
+                eprintln!("xinput not usable ({}), falling back to evdev", err);
+                return key_device_setup(Discovery::Evdev, isolation, vid, pid, dev_filter);
+            },
+            Err(err) => return Err(err.context("getting devices listed by xinput")),
+        };
+        // only slave devices with an event node can be read (XWayland
+        // lists its devices without one)
+        xdevs.retain(|x| x.slave && !x.device.is_empty());
+        if xdevs.is_empty() && discovery == Discovery::Auto {
+            eprintln!("xinput lists no device with an event node, falling back to evdev");
+            return key_device_setup(Discovery::Evdev, isolation, vid, pid, dev_filter);
+        }
+        xdevs
+    } else {
+        let mut xdevs = read_evdev()
+            .context("getting devices listed by the kernel")?;
+        xdevs.retain(|x| x.keyboard);
+        xdevs
+    };
+    // never read the key strokes keybuddy sends itself
+    xdevs.retain(|x| x.name != VIRTUAL_KEYBOARD_NAME);
     if let Some(vid) = vid {
-        xdevs = xdevs
-            .into_iter()
-            .filter(|x| x.usb_vid == vid)
-            .collect();
+        xdevs.retain(|x| x.usb_vid == vid);
     }
     if let Some(pid) = pid {
-        xdevs = xdevs
-            .into_iter()
-            .filter(|x| x.usb_pid == pid)
-            .collect();
+        xdevs.retain(|x| x.usb_pid == pid);
     }
 
     if let Some(fun) = dev_filter {
-    xdevs = xdevs
-        .into_iter()
-        .filter(|x| fun(&x.name))
-        .collect();
+        xdevs.retain(|x| fun(&x.name));
     }
 
     if xdevs.is_empty() {
-        bail!("No matching X-input devices found !");
+        return Err(NoMatchingDevice.into());
     }
     if xdevs.len() > 1 {
-        bail!(format!("Only 1 x-input device should match, but found {}", xdevs.len()));
+        bail!(format!("Only 1 input device should match, but found {}", xdevs.len()));
     }
     let xdev = xdevs.first().unwrap();
-    if ! xdev.floating {
-        let _ = Command::new("xinput")
-            .arg("float")
-            .arg(xdev.id.to_string())
-            .output()
-            .context("Making device 'float'")?;
-    }
-    Ok(xdev.device.clone())
+    let mut key_device = KeyDevice {
+        device: xdev.device.clone(),
+        reattach: None,
+    };
+    if isolation == Isolation::Float {
+        if ! use_xinput {
+            eprintln!("Device {} found without xinput, not making it float", xdev.device);
+        } else if ! xdev.floating {
+            let _ = Command::new("xinput")
+                .arg("float")
+                .arg(xdev.id.to_string())
+                .output()
+                .context("Making device 'float'")?;
+            key_device.reattach = xdev.attached_to.map(|master| (xdev.id, master));
+        }
+    }
+    Ok(key_device)
+}
+
+/// State of a key as reported in the value field of an EV_KEY event
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyState {
+    Released,
+    Pressed,
+    Repeated,
+}
+
+/// A key being pressed or released, tagged with the index of the
+/// device it came from
+#[derive(Clone, Debug)]
+pub struct KeyEvent {
+    pub source: usize,
+    /// event node the key was read from
+    pub device: Arc<str>,
+    pub code: u16,
+    pub state: KeyState,
+    /// time stamp of the event as set by the kernel
+    pub time: Duration,
+}
+
+/// Reason why `key_reader_task` returned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReaderExit {
+    /// Something was received via stop_rx
+    Stopped,
+    /// The device could not be opened or read (e.g. it was unplugged)
+    Failed,
 }
 
 /// Task waits for key strokes and feeds these into ev_tx. It aborts
-/// when anything is received via stop_rx.
+/// when anything is received via stop_rx, or when the device cannot
+/// be read anymore.
+///
+/// With `grab` set, the device is grabbed exclusively for as long as
+/// the task runs. All events are tagged with `source`.
 ///
 pub async fn key_reader_task(file_name: &String,
-                             ev_tx: mpsc::Sender<u16>,
-                             mut stop_rx: mpsc::Receiver<()>)
+                             source: usize,
+                             grab: bool,
+                             ev_tx: mpsc::Sender<KeyEvent>,
+                             stop_rx: &mut mpsc::Receiver<()>)
+                             -> ReaderExit
 {
     let file = File::open(file_name).await;
     if file.is_err() {
         eprintln!("Could not open device {}", file_name);
-        return;
+        return ReaderExit::Failed;
     }
     let mut file = file.unwrap();
+    let _grab = if grab {
+        match Grab::new(file.as_raw_fd()) {
+            Ok(grab) => Some(grab),
+            Err(err) => {
+                eprintln!("Could not grab device {}: {}", file_name, err);
+                return ReaderExit::Failed;
+            }
+        }
+    } else {
+        None
+    };
     eprintln!("Listening on device {} ...", file_name);
+    let device: Arc<str> = Arc::from(file_name.as_str());
 
     loop {
         let mut packet = [0u8; 24];
         let rd = file.read_exact(&mut packet);
 
         tokio::select! {
-            _ = rd => {
+            res = rd => {
+                if let Err(err) = res {
+                    eprintln!("Could not read from device {}: {}", file_name, err);
+                    return ReaderExit::Failed;
+                }
                 let mut rdr = Cursor::new(packet);
-                let _tv_sec  = ReadBytesExt::read_u64::<NativeEndian>(&mut rdr).unwrap();
-                let _tv_usec = ReadBytesExt::read_u64::<NativeEndian>(&mut rdr).unwrap();
+                let tv_sec  = ReadBytesExt::read_u64::<NativeEndian>(&mut rdr).unwrap();
+                let tv_usec = ReadBytesExt::read_u64::<NativeEndian>(&mut rdr).unwrap();
                 let evtype  = ReadBytesExt::read_u16::<NativeEndian>(&mut rdr).unwrap();
                 let code    = ReadBytesExt::read_u16::<NativeEndian>(&mut rdr).unwrap();
                 let value   = ReadBytesExt::read_i32::<NativeEndian>(&mut rdr).unwrap();
 
-                if evtype == EventType::EvKey as u16 && value == 1 {
-                    ev_tx.send(code).await.unwrap();
+                if evtype == EventType::EvKey as u16 {
+                    let state = match value {
+                        0 => KeyState::Released,
+                        1 => KeyState::Pressed,
+                        2 => KeyState::Repeated,
+                        _ => continue,
+                    };
+                    let time = Duration::from_secs(tv_sec) + Duration::from_micros(tv_usec);
+                    if ev_tx.send(KeyEvent { source, device: device.clone(), code, state, time }).await.is_err() {
+                        return ReaderExit::Stopped;
+                    }
                 }
             },
             _ = stop_rx.recv() => {
-                break;
+                return ReaderExit::Stopped;
             }
         }
     }
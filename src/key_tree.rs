@@ -1,14 +1,223 @@
 //! Tree of keystrokes with commands as leaf nodes
 
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use indextree::{Arena, NodeId};
-use regex::Regex;
-use std::sync::OnceLock;
-use crate::key_codes::key_name_from_code;
+use regex::{Captures, Regex};
+use anyhow::{Result, bail};
+use crate::action::Action;
+use crate::key_codes::{all_key_codes, key_name_from_code};
+
+/// Class of keys matched by a wildcard step (`<digit>`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyClass {
+    /// KEY_0 ... KEY_9 and KEY_KP0 ... KEY_KP9; captures the digit
+    Digit,
+    /// one or more digits; captures the number
+    Number,
+    /// the arrow keys, and 8, 2, 4 and 6 on the keypad; captures up,
+    /// down, left or right
+    Arrow,
+    /// any key; captures the key name
+    Any,
+}
+
+impl KeyClass {
+    /// Parse the name of a class as used in `<digit>`
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "digit" => Ok(Self::Digit),
+            "number" => Ok(Self::Number),
+            "arrow" => Ok(Self::Arrow),
+            "any" => Ok(Self::Any),
+            _ => bail!("Unknown key class '{}' (use digit, number, arrow, or any)", name),
+        }
+    }
+
+    /// The name of the class
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Digit => "digit",
+            Self::Number => "number",
+            Self::Arrow => "arrow",
+            Self::Any => "any",
+        }
+    }
+
+    /// The value captured when `key` belongs to the class, or None if
+    /// it does not
+    pub fn capture(&self, key: u16) -> Option<String> {
+        let name = key_name_from_code(key);
+        match self {
+            Self::Digit | Self::Number => {
+                let name = name?;
+                let digit = name.strip_prefix("KEY_KP").or(name.strip_prefix("KEY_"))?;
+                (digit.len() == 1 && digit.as_bytes()[0].is_ascii_digit()).then(|| digit.to_string())
+            },
+            Self::Arrow => match name? {
+                "KEY_UP" | "KEY_KP8" => Some("up".to_string()),
+                "KEY_DOWN" | "KEY_KP2" => Some("down".to_string()),
+                "KEY_LEFT" | "KEY_KP4" => Some("left".to_string()),
+                "KEY_RIGHT" | "KEY_KP6" => Some("right".to_string()),
+                _ => None,
+            },
+            Self::Any => Some(name.map_or(key.to_string(), |name| name.to_string())),
+        }
+    }
+}
+
+/// One step of a key sequence: a single key, or a chord of several
+/// keys that are held down together
+///
+/// The keys are kept sorted, so that the order in which the keys of
+/// a chord are pressed does not matter. A "long" step only matches
+/// if the keys are held longer than the long-press threshold.
+///
+/// In the tree, a step can also be a wildcard that matches any single
+/// key of a class; key strokes themselves are never wildcards.
+///
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct KeyStep {
+    keys: Vec<u16>,
+    long: bool,
+    class: Option<KeyClass>,
+}
+
+impl KeyStep {
+    /// Create a step from one or several keys
+    pub fn new(keys: &[u16]) -> Self {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        Self { keys, long: false, class: None }
+    }
+
+    /// Create a wildcard step matching any key of `class`
+    pub fn wildcard(class: KeyClass) -> Self {
+        Self { keys: vec![], long: false, class: Some(class) }
+    }
+
+    /// Turn the step into a long-press step (or back into a tap)
+    pub fn with_long(mut self, long: bool) -> Self {
+        self.long = long;
+        self
+    }
+
+    /// The keys of the step
+    pub fn keys(&self) -> &[u16] {
+        &self.keys
+    }
+
+    /// True if all keys of `other` are part of this step
+    pub fn contains(&self, other: &KeyStep) -> bool {
+        other.keys.iter().all(|k| self.keys.contains(k))
+    }
+
+    /// The value captured if this step is a wildcard matching the
+    /// single-key step `step`
+    fn capture(&self, step: &KeyStep) -> Option<String> {
+        match (self.class, step.keys.as_slice()) {
+            (Some(class), [key]) if self.long == step.long => class.capture(*key),
+            _ => None,
+        }
+    }
+
+    /// True if this wildcard step matches every key stroke that the
+    /// wildcard step `other` matches
+    fn covers(&self, other: &KeyStep) -> bool {
+        match (self.class, other.class) {
+            (Some(class), Some(other_class)) => self.long == other.long && all_key_codes()
+                .filter(|k| other_class.capture(*k).is_some())
+                .all(|k| class.capture(k).is_some()),
+            _ => false,
+        }
+    }
+
+    /// Precedence of the step when several match the same key
+    /// stroke: exact keys (0) before `<digit>`, `<number>` and
+    /// `<arrow>` (1), before `<any>` (2)
+    fn rank(&self) -> u8 {
+        match self.class {
+            None => 0,
+            Some(KeyClass::Any) => 2,
+            Some(_) => 1,
+        }
+    }
+
+    /// True if the step may be repeated (`<number>`)
+    pub fn repeats(&self) -> bool {
+        self.class == Some(KeyClass::Number)
+    }
+
+    /// True if the key strokes of `step` complete this step, either
+    /// as the same keys or as a key matching a wildcard
+    pub fn matches(&self, step: &KeyStep) -> bool {
+        self == step || self.capture(step).is_some()
+    }
+
+    /// True if this step might still be completed when the keys of
+    /// `held` stay down: it is a chord with further keys, or a long
+    /// press of these keys
+    pub fn extends(&self, held: &KeyStep) -> bool {
+        match (self.class, held.keys.as_slice()) {
+            (Some(class), [key]) => self.long && class.capture(*key).is_some(),
+            (Some(_), _) => false,
+            (None, _) => self != held && self.contains(held),
+        }
+    }
+}
+
+impl fmt::Debug for KeyStep {
+    /// Show the step with short key names (KP0+KP5, KP1:long) as used
+    /// in the tree dump
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(class) = self.class {
+            write!(f, "<{}>", class.name())?;
+        } else if self.keys.is_empty() {
+            return write!(f, "0");
+        }
+        let names : Vec<String> = self.keys
+            .iter()
+            .map(|k| match key_name_from_code(*k) {
+                Some(name) => name.replace("KEY_", ""),
+                None => k.to_string(),
+            })
+            .collect();
+        write!(f, "{}", names.join("+"))?;
+        if self.long {
+            write!(f, ":long")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for KeyStep {
+    /// Show the step in config file syntax (KEY_KP0+KEY_KP5,
+    /// KEY_KP1:long, <digit>)
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(class) = self.class {
+            write!(f, "<{}>", class.name())?;
+        }
+        let names : Vec<String> = self.keys
+            .iter()
+            .map(|k| match key_name_from_code(*k) {
+                Some(name) => name.to_string(),
+                None => k.to_string(),
+            })
+            .collect();
+        write!(f, "{}", names.join("+"))?;
+        if self.long {
+            write!(f, ":long")?;
+        }
+        Ok(())
+    }
+}
 
 /// A sequence of key strokes
 ///
 #[derive(Debug, Clone)]
-pub struct KeySequence( Vec<u16> );
+pub struct KeySequence( Vec<KeyStep> );
 
 impl PartialEq for KeySequence {
     /// check if two KeySequences are equal
@@ -21,18 +230,160 @@ impl PartialEq for KeySequence {
 impl Eq for KeySequence {}
 
 impl From<&Vec<u16>> for KeySequence {
-    /// turn &Vec<u16> into a KeySequence
+    /// turn &Vec<u16> into a KeySequence of single keys
     fn from(seq: &Vec<u16>) -> Self {
+        Self (seq.iter().map(|k| KeyStep::new(&[*k])).collect())
+    }
+}
+
+impl From<&Vec<KeyStep>> for KeySequence {
+    /// turn &Vec<KeyStep> into a KeySequence
+    fn from(seq: &Vec<KeyStep>) -> Self {
         Self (seq.clone())
     }
 }
 
-/// A tree that holds the key sequences and associated Strings
+/// How a command is run with respect to earlier invocations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunMode {
+    /// start the command in the background (default)
+    Detach,
+    /// wait until the command has terminated before processing
+    /// further keys
+    Wait,
+    /// kill the instance started previously, if still running
+    Restart,
+}
+
+/// An action bound to a key sequence, with its options
+#[derive(Clone, PartialEq)]
+pub struct Binding {
+    pub action: Action,
+    /// fire again on autorepeat events
+    pub repeat: bool,
+    /// minimum time between repetitions, overriding `repeat_interval`
+    pub repeat_interval: Option<Duration>,
+    /// execute the command directly instead of through the shell
+    pub exec: bool,
+    /// how the command is run
+    pub mode: RunMode,
+    /// kill the command if it runs longer (zero: never), overriding
+    /// `command_timeout`
+    pub timeout: Option<Duration>,
+    /// file and line where the binding was defined
+    pub origin: Option<(Arc<str>, usize)>,
+}
+
+impl Binding {
+    /// Create a binding without any options
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            repeat: false,
+            repeat_interval: None,
+            exec: false,
+            mode: RunMode::Detach,
+            timeout: None,
+            origin: None,
+        }
+    }
+}
+
+impl Binding {
+    /// The binding with the captured values substituted for `{1}`,
+    /// `{2}` etc. in the action, and the number captured by the last
+    /// `<number>` step for `{n}`
+    ///
+    /// Placeholders without a captured value are left alone.
+    ///
+    pub fn with_captures(&self, captures: &[String], number: Option<&str>) -> Binding {
+        static RX: OnceLock<Regex> = OnceLock::new();
+        let rx = RX.get_or_init(
+            || Regex::new(r##"\{([1-9][0-9]*|n)\}"##).unwrap()
+        );
+        if captures.is_empty() {
+            return self.clone();
+        }
+        let action = self.action.map_text(&|text| {
+            rx.replace_all(text, |caps: &Captures| {
+                let value = match &caps[1] {
+                    "n" => number,
+                    index => index.parse::<usize>()
+                        .ok()
+                        .and_then(|i| i.checked_sub(1))
+                        .and_then(|i| captures.get(i))
+                        .map(|x| x.as_str()),
+                };
+                value.unwrap_or(&caps[0]).to_string()
+            }).to_string()
+        });
+        Binding { action, ..self.clone() }
+    }
+
+    /// The options that differ from the defaults, in config file
+    /// syntax
+    pub fn options(&self) -> Vec<String> {
+        let mut opts = vec![];
+        match (self.repeat, self.repeat_interval) {
+            (true, Some(interval)) => opts.push(format!("repeat={}", interval.as_secs_f32())),
+            (true, None) => opts.push("repeat".to_string()),
+            _ => {},
+        }
+        if self.exec {
+            opts.push("exec".to_string());
+        }
+        match self.mode {
+            RunMode::Wait => opts.push("wait".to_string()),
+            RunMode::Restart => opts.push("restart".to_string()),
+            RunMode::Detach => {},
+        }
+        if let Some(timeout) = self.timeout {
+            opts.push(format!("timeout={}", timeout.as_secs_f32()));
+        }
+        opts
+    }
+}
+
+impl fmt::Debug for Binding {
+    /// Show the action, followed by the options if there are any
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.action)?;
+        let opts = self.options();
+        if !opts.is_empty() {
+            write!(f, " [{}]", opts.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A key sequence of the tree that has a binding, as listed by
+/// `KTree::bindings`
+pub struct BoundSequence<'a> {
+    pub steps: Vec<KeyStep>,
+    pub binding: &'a Binding,
+    /// longer sequences start with this one, so that its binding
+    /// only fires once no further key follows within `delay`
+    pub prefix: bool,
+    /// a wildcard step with higher precedence always matches the same
+    /// key strokes and has a binding, so that this binding never fires
+    pub shadowed: bool,
+}
+
+/// Values captured by the wildcards along a path through the tree
+#[derive(Clone, Default)]
+struct Captured {
+    values: Vec<String>,
+    /// index of the value captured by the last `<number>`
+    number: Option<usize>,
+}
+
+/// A tree that holds the key sequences and associated Bindings
 /// (e.g. commands). The tree is implemented as indextree, which works
 /// well because no members are ever deleted.
 ///
+#[derive(Clone)]
 pub struct KTree {
-    arena: Arena<(u16, Option<String>)>,
+    arena: Arena<(KeyStep, Option<Binding>)>,
     root: NodeId,
 }
 
@@ -40,54 +391,141 @@ impl KTree {
     /// Create a new empty KTree
     pub fn new() -> Self {
         let mut arena = Arena::new();
-        let root = arena.new_node((0, None));
+        let root = arena.new_node((KeyStep::new(&[]), None));
         Self { arena, root }
     }
 
     /// Dump the tree to stderr for debugging purposes
     pub fn dump(&self) {
-        let text = format!("{:?}\n", self.root.debug_pretty_print(&self.arena)).to_string();
-        static RX1: OnceLock<Regex> = OnceLock::new();
-        let rx1 = RX1.get_or_init(
-            || Regex::new(r##"-- \(([0-9]+),"##).unwrap()
-        );
-        let mut new = String::with_capacity(text.len());
-        let mut last_match = 0;
-        for hit in rx1.captures_iter(&text) {
-            let m = hit.get(1).unwrap();
-            let code = m.as_str().parse::<u16>().unwrap();
-            if let Some(code) = key_name_from_code(code) {
-                let short_code = code.replace("KEY_", "");
-                new.push_str(&text[last_match..m.start()]);
-                new.push_str(&short_code);
-                last_match = m.end();
+        eprintln!("key-command association tree:\n{:?}\n", self.root.debug_pretty_print(&self.arena));
+    }
+
+    /// Walk the tree along `seq` (a sequence of key strokes) and
+    /// return all nodes reached, together with the values captured by
+    /// wildcards on the way
+    ///
+    /// The nodes are ordered by precedence: at each step, a child with
+    /// exactly the keys of the step wins over wildcards, and `<digit>`,
+    /// `<number>` and `<arrow>` win over `<any>`.
+    ///
+    /// A `<number>` node is reached by its first digit and stays the
+    /// current node while further digits follow; these are appended to
+    /// its captured value.
+    ///
+    fn walk(&self, seq: &KeySequence) -> Vec<(NodeId, Captured)> {
+        let mut reached = vec![(self.root, Captured::default())];
+        for key in &seq.0 {
+            let mut next = vec![];
+            for (node_id, captured) in reached {
+                let mut children : Vec<(u8, NodeId, Captured)> = vec![];
+                for ch in node_id.children(&self.arena) {
+                    let node = &self.arena.get(ch).unwrap().get().0;
+                    if node == key {
+                        children.push((0, ch, captured.clone()));
+                    } else if let Some(value) = node.capture(key) {
+                        let rank = node.rank();
+                        let mut captured = captured.clone();
+                        if node.repeats() {
+                            captured.number = Some(captured.values.len());
+                        }
+                        captured.values.push(value);
+                        children.push((rank, ch, captured));
+                    }
+                }
+                let node = &self.arena.get(node_id).unwrap().get().0;
+                if let (true, Some(value), Some(number)) = (node.repeats(), node.capture(key), captured.number) {
+                    let mut captured = captured.clone();
+                    captured.values[number].push_str(&value);
+                    children.push((1, node_id, captured));
+                }
+                children.sort_by_key(|(rank, _, _)| *rank);
+                next.extend(children.into_iter().map(|(_, ch, captured)| (ch, captured)));
             }
+            reached = next;
         }
-        new.push_str(&text[last_match..]);
-        eprintln!("key-command association tree:\n{}", new);
+        reached
     }
 
-    /// Look up a KeySequence by walking the tree and return the
-    /// associated command if found, or None
-    pub fn find<'a>(&'a self, seq: &KeySequence) -> &'a Option<String> {
-        let mut node_id = self.root;
-        'key_loop:
-        for key in &seq.0 {
+    /// Return the steps that may follow `seq`
+    ///
+    /// After the digits of a `<number>`, this includes the `<number>`
+    /// step itself.
+    ///
+    pub fn next_steps<'a>(&'a self, seq: &KeySequence) -> Vec<&'a KeyStep> {
+        let mut steps = vec![];
+        for (node_id, _) in self.walk(seq) {
+            let node = &self.arena.get(node_id).unwrap().get().0;
+            if node.repeats() && !steps.contains(&node) {
+                steps.push(node);
+            }
             for ch in node_id.children(&self.arena) {
-                let node = self.arena.get(ch).unwrap().get();
-                if node.0 == *key {
-                    node_id = ch;
-                    continue 'key_loop;
+                let step = &self.arena.get(ch).unwrap().get().0;
+                if !steps.contains(&step) {
+                    steps.push(step);
                 }
             }
-            return &None;
         }
-        &self.arena.get(node_id).unwrap().get().1
+        steps
+    }
+
+    /// Look up a KeySequence by walking the tree and return the
+    /// associated binding if found, or None
+    ///
+    /// If `seq` matches several sequences of the tree, the binding
+    /// with the highest precedence is returned. The values captured
+    /// by wildcards are substituted into its command.
+    ///
+    pub fn find(&self, seq: &KeySequence) -> Option<Binding> {
+        self.walk(seq)
+            .into_iter()
+            .find_map(|(node_id, captured)| {
+                let binding = self.arena.get(node_id).unwrap().get().1.as_ref()?;
+                let number = captured.number.map(|i| captured.values[i].as_str());
+                Some(binding.with_captures(&captured.values, number))
+            })
+    }
+
+    /// List the sequences that have a binding, in depth-first order
+    pub fn bindings(&self) -> Vec<BoundSequence<'_>> {
+        let mut result = vec![];
+        for node_id in self.root.descendants(&self.arena) {
+            let node = self.arena.get(node_id).unwrap();
+            let Some(binding) = &node.get().1 else {
+                continue;
+            };
+            let mut steps : Vec<KeyStep> = node_id
+                .ancestors(&self.arena)
+                .filter(|id| *id != self.root)
+                .map(|id| self.arena.get(id).unwrap().get().0.clone())
+                .collect();
+            steps.reverse();
+            let step = &node.get().0;
+            // siblings of the same rank take precedence in the order
+            // they were added
+            let covered = |id: NodeId, earlier: bool| {
+                let (sibling, binding) = self.arena.get(id).unwrap().get();
+                binding.is_some()
+                    && sibling.covers(step)
+                    && (sibling.rank() < step.rank() || (earlier && sibling.rank() == step.rank()))
+            };
+            let shadowed = node_id.preceding_siblings(&self.arena).skip(1).any(|id| covered(id, true))
+                || node_id.following_siblings(&self.arena).skip(1).any(|id| covered(id, false));
+            result.push(BoundSequence {
+                steps,
+                binding,
+                prefix: node_id.children(&self.arena).next().is_some(),
+                shadowed,
+            });
+        }
+        result
     }
 
-    /// Add 'seq' (a KeySequence) and the associated 'command' (a
-    /// String) to the tree
-    pub fn add(&mut self, seq: &KeySequence, command: Option<String>) {
+    /// Add 'seq' (a KeySequence) and the associated 'binding' to the
+    /// tree
+    ///
+    /// Returns the binding that `seq` had before, if any.
+    ///
+    pub fn add(&mut self, seq: &KeySequence, binding: Option<Binding>) -> Option<Binding> {
         let mut node_id = self.root;
         'key_loop:
         for key in &seq.0 {
@@ -98,10 +536,60 @@ impl KTree {
                     continue 'key_loop;
                 }
             }
-            node_id = node_id.append_value((*key, None), &mut self.arena);
+            node_id = node_id.append_value((key.clone(), None), &mut self.arena);
         }
         let (ref mut _key, ref mut cmd) = self.arena.get_mut(node_id).unwrap().get_mut();
-        *cmd = command;
+        std::mem::replace(cmd, binding)
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_codes::code_from_key_name;
+
+    /// The step for the keys named in `names`
+    fn step(names: &[&str]) -> KeyStep {
+        let keys : Vec<u16> = names.iter().map(|n| code_from_key_name(n).unwrap()).collect();
+        KeyStep::new(&keys)
+    }
+
+    /// Bind the sequence `steps` to the command `cmd`
+    fn bind(tree: &mut KTree, steps: Vec<KeyStep>, cmd: &str) {
+        tree.add(&KeySequence::from(&steps), Some(Binding::new(Action::Command(cmd.to_string()))));
+    }
+
+    /// The command bound to the key strokes `names`
+    fn command(tree: &KTree, names: &[&str]) -> Option<String> {
+        let steps : Vec<KeyStep> = names.iter().map(|n| step(&[n])).collect();
+        match tree.find(&KeySequence::from(&steps))?.action {
+            Action::Command(cmd) => Some(cmd),
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        // <any> is added first, but the other steps take precedence
+        let mut tree = KTree::new();
+        let slash = step(&["KEY_KPSLASH"]);
+        bind(&mut tree, vec![slash.clone(), KeyStep::wildcard(KeyClass::Any)], "any {1}");
+        bind(&mut tree, vec![slash.clone(), KeyStep::wildcard(KeyClass::Digit)], "digit {1}");
+        bind(&mut tree, vec![slash.clone(), KeyStep::wildcard(KeyClass::Arrow)], "arrow {1}");
+        bind(&mut tree, vec![slash.clone(), step(&["KEY_KP5"])], "five");
+
+        assert_eq!(command(&tree, &["KEY_KPSLASH", "KEY_KP5"]).as_deref(), Some("five"));
+        assert_eq!(command(&tree, &["KEY_KPSLASH", "KEY_KP3"]).as_deref(), Some("digit 3"));
+        assert_eq!(command(&tree, &["KEY_KPSLASH", "KEY_UP"]).as_deref(), Some("arrow up"));
+        assert_eq!(command(&tree, &["KEY_KPSLASH", "KEY_A"]).as_deref(), Some("any KEY_A"));
+        assert_eq!(command(&tree, &["KEY_KPDOT"]), None);
+    }
+
+    #[test]
+    fn placeholders() {
+        let binding = Binding::new(Action::Command("x {1} {2} {99999999999999999999}".to_string()));
+        let binding = binding.with_captures(&["a".to_string()], None);
+        assert_eq!(binding.action, Action::Command("x a {2} {99999999999999999999}".to_string()));
+    }
+}
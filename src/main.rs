@@ -1,36 +1,140 @@
 use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::process::Command;
+use std::time::Duration;
 
-use clap::Parser;
-use anyhow::{Result, Context};
+use clap::{Parser, Subcommand};
+use anyhow::{Result, Context, bail};
 use tokio::sync::mpsc;
-use tokio::time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, sleep_until, timeout, Instant};
 
+mod action;
 mod key_tree;
 mod key_codes;
 mod xinput;
+mod evdev;
 mod key_reader;
+mod inotify;
+mod hotplug;
+mod dispatcher;
+mod executor;
+mod uinput;
 mod config;
+mod toml_config;
+mod check;
 
-use config::{KeyValueData, KeyValueStore};
-
-use key_reader::{key_device_setup, key_reader_task};
+use action::Action;
+use key_reader::{KeyEvent, KeyState};
+use hotplug::{device_supervisor, same_devices, DeviceSpec};
+use dispatcher::Dispatcher;
+use executor::Executor;
 use key_codes::key_name_from_code;
-use key_tree::{KTree, KeySequence};
-use config::init_from_file;
+use config::{config_files, glob_match, init_from_file, seconds, Config};
+use inotify::{Inotify, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_MOVED_TO};
+
+/// Time without further changes to the config file before it is
+/// reloaded, so that an editor has finished saving it
+const RELOAD_SETTLE: Duration = Duration::from_millis(300);
 
-/// Execute a command and return true if ok, otherwise false
+/// Read the configuration from `files`, in this order
+///
+/// Problems in the files are reported and the lines concerned are
+/// ignored, unless `strict` is true or the files set `strict = 1`, in
+/// which case the configuration is rejected.
 ///
-/// The command is passed as single str including all the arguments.
+fn load_config(files: &[String], strict: bool) -> Result<Config> {
+    if files.is_empty() {
+        bail!("No config file found");
+    }
+    let mut config = Config::new();
+    for file in files {
+        init_from_file(file, &mut config)?;
+    }
+    for error in config.errors.iter().chain(&config.warnings) {
+        eprintln!("{}", error);
+    }
+    let strict = strict || config.kv.get_float("strict").is_some_and(|x| x != 0.0);
+    if strict && !config.errors.is_empty() {
+        bail!("{} invalid line(s) in {}", config.errors.len(), files.join(", "));
+    }
+    Ok(config)
+}
+
+/// Task that sends a message via `reload_tx` whenever one of the
+/// config files in `sources` has been changed, or a file matching one
+/// of the patterns among them has been added
 ///
-fn exec_command(cmd: &str) -> bool {
-    if let Some(parts) = shlex::split(cmd) {
-        if let Some((cmd, args)) = parts.split_first() {
-            return Command::new(cmd).args(args).output().is_ok()
+/// The directories are watched rather than the files, because many
+/// editors save a file by replacing it.
+///
+async fn watch_config(sources: Vec<PathBuf>, reload_tx: mpsc::Sender<()>) {
+    let inotify = match Inotify::new() {
+        Ok(inotify) => inotify,
+        Err(err) => {
+            eprintln!("{}, not watching the config files for changes", err);
+            return;
+        },
+    };
+    // file name patterns by watch descriptor
+    let mut patterns : HashMap<i32, Vec<String>> = HashMap::new();
+    for source in &sources {
+        let dir = match source.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Some(name) = source.file_name().map(|x| x.to_string_lossy().to_string()) else {
+            continue;
+        };
+        match inotify.add_watch(dir, IN_CLOSE_WRITE | IN_MOVED_TO | IN_CREATE | IN_DELETE) {
+            Ok(wd) => patterns.entry(wd).or_default().push(name),
+            Err(err) => eprintln!("{}, not watching {} for changes", err, source.display()),
+        }
+    }
+    if patterns.is_empty() {
+        return;
+    }
+    loop {
+        let Ok(events) = inotify.read_events().await else {
+            return;
+        };
+        let changed = events.iter().any(|ev| patterns
+                                         .get(&ev.wd)
+                                         .is_some_and(|names| names.iter().any(|n| glob_match(n, &ev.name))));
+        if !changed {
+            continue;
+        }
+        // wait until the files have not been touched for a while
+        while let Ok(Ok(_)) = timeout(RELOAD_SETTLE, inotify.read_events()).await {}
+        if reload_tx.send(()).await.is_err() {
+            return;
         }
     }
-    false
+}
+
+/// Install handlers for SIGINT (Ctrl-C) and SIGTERM, and return a
+/// future that completes when one of them is received
+///
+/// The handlers are installed right away, not when the future is
+/// first polled, so that no signal received in between is lost.
+///
+fn termination_signal() -> Result<impl Future<Output = ()>> {
+    let mut sigint = signal(SignalKind::interrupt()).context("Installing SIGINT handler")?;
+    let mut sigterm = signal(SignalKind::terminate()).context("Installing SIGTERM handler")?;
+    Ok(async move {
+        tokio::select! {
+            _ = sigint.recv() => {},
+            _ = sigterm.recv() => {},
+        }
+    })
+}
+
+/// Parse a command-line argument given in seconds
+fn parse_seconds(arg: &str) -> Result<f32> {
+    let secs = arg.parse::<f32>().context("not a number")?;
+    seconds(secs).context("not a valid number of seconds")?;
+    Ok(secs)
 }
 
 #[derive(Parser, Debug)]
@@ -42,135 +146,278 @@ struct Args {
     show_keys: bool,
 
     /// Set maximum time span between keystrokes that form a sequence
-    #[arg(short='d', long="delay", id="SECONDS", default_value_t = 2.0)]
+    #[arg(short='d', long="delay", id="SECONDS", default_value_t = 2.0, value_parser = parse_seconds)]
     key_memory_span: f32,
 
-    /// Use config file
-    #[arg(long, default_value_t = std::env::var("HOME").unwrap()+"/.config/keybuddy.conf")]
-    cfg_file: String,
+    /// Use config file, read after /etc/keybuddy.conf and
+    /// ~/.config/keybuddy.conf, if these exist
+    #[arg(long)]
+    cfg_file: Option<String>,
 
-    /// Be verbose (for debugging)
-    #[arg(short='v', long="verbose", default_value_t = false)]
-    debug: bool,
+    /// Be verbose (for debugging); repeat to also show the output of commands
+    #[arg(short='v', long="verbose", action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Check the config file for problems without touching any device,
+    /// and exit with a failure status if there are any
+    Check {
+        /// Config file to check, instead of the files read on startup
+        file: Option<String>,
+    },
+    /// Convert a config file between the line format and TOML; the
+    /// output format follows from the extension (.toml or other)
+    Convert {
+        /// Config file to convert
+        input: String,
+        /// File to write, which must not exist yet; without it, the
+        /// input is converted to the other format and written to stdout
+        output: Option<String>,
+    },
 }
 
 
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
     // command-line arguments
-    let mut opts = Args::parse();
+    let opts = Args::parse();
+
+    if let Some(Commands::Check { file }) = &opts.command {
+        let files = match file {
+            Some(file) => vec![file.clone()],
+            None => config_files(opts.cfg_file.as_deref()),
+        };
+        return check::check_config(&files);
+    }
+    if let Some(Commands::Convert { input, output }) = &opts.command {
+        toml_config::convert(input, output.as_deref())?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
     eprintln!("KeyBuddy -- (C) 2024 Pascal Niklaus");
 
     // read config file
-    let mut kt = KTree::new();
-    let mut kv = KeyValueStore(HashMap::<String, KeyValueData>::new());
-    init_from_file(&opts.cfg_file, &mut kt, &mut kv).context("Reading config file")?;
+    let files = config_files(opts.cfg_file.as_deref());
+    let config = load_config(&files, false).context("Reading config file")?;
+    let mut sources = config.sources.clone();
 
-    if let Some(delay) = kv.get_float("delay") {
-        opts.key_memory_span = delay;
-    }
+    let mut quit = config.kv.get_str("quit_command").map(|x| x.to_string());
+    let debug = opts.verbose > 0;
+    let mut executor = Executor::new(&config.kv, opts.verbose);
+    let devices = config.into_devices();
 
-    // collect all input devices
-    let mut vid = None;
-    if let Some(KeyValueData::Int(v)) = kv.get("vid") {
-        vid = Some(v as u16);
-    }
-    let mut pid = None;
-    if let Some(KeyValueData::Int(v)) = kv.get("pid") {
-        pid = Some(v as u16);
-    }
-    let mut f1 : Box<dyn Fn(&str)->bool> = Box::new(|_x: &str| -> bool { true });
-    let mut f2 : Box<dyn Fn(&str)->bool> = Box::new(|_x: &str| -> bool { true });
-    if let Some(KeyValueData::Text(v)) = kv.get("device_include") {
-        f1 = Box::new(move |x: &str| -> bool { x.contains(v.as_str()) });
-    }
-    if let Some(KeyValueData::Text(v)) = kv.get("device_exclude") {
-        f2 = Box::new(move |x: &str| -> bool { !x.contains(v.as_str()) });
+    // one supervisor per device filters the devices, makes the one
+    // found float, and reads from it whenever it is connected
+    let mut specs = vec![];
+    for (source, dev) in devices.iter().enumerate() {
+        specs.push(DeviceSpec::from_config(source, dev)
+                   .context(format!("Setting up device {}", dev.name))?);
     }
 
-    // filter devices, make the one found float, and extracts its name
-    let dev_name = key_device_setup(vid, pid, Some(Box::new(move |x| { f1(x) & f2(x) })));
-    if let Err(err) = dev_name {
-        eprintln!("An error occurred: {}", err);
-        return Ok(ExitCode::FAILURE);
-    }
-    let dev_name = dev_name.unwrap().to_string();
+    // from here on, the devices are restored when terminated by a signal
+    let terminated = termination_signal()?;
+    tokio::pin!(terminated);
+    let mut exit_code = ExitCode::SUCCESS;
 
     // communication channels
-    let (ev_tx, mut ev_rx) = mpsc::channel::<u16>(10);
-    let (stop_tx, stop_rx) = mpsc::channel::<()>(1);
+    let (ev_tx, mut ev_rx) = mpsc::channel::<KeyEvent>(10);
+    let (fail_tx, mut fail_rx) = mpsc::channel::<anyhow::Error>(1);
 
-    // spawn keystroke reader
-    let task_handle = tokio::spawn(async move {
-        key_reader_task(&dev_name, ev_tx, stop_rx).await;
-    });
+    // spawn device supervisors and keystroke readers
+    let mut tasks = vec![];
+    for spec in specs {
+        let (stop_tx, stop_rx) = mpsc::channel::<()>(1);
+        let ev_tx = ev_tx.clone();
+        let fail_tx = fail_tx.clone();
+        // ev_tx is only dropped once the error has been sent, so that
+        // it is there when the event channel is found closed
+        let task_handle = tokio::spawn(async move {
+            if let Err(err) = device_supervisor(spec, ev_tx.clone(), stop_rx).await {
+                let _ = fail_tx.send(err).await;
+            }
+            drop(ev_tx);
+        });
+        tasks.push((stop_tx, task_handle));
+    }
+    drop(ev_tx);
+    drop(fail_tx);
+
+    let mut dispatcher = Dispatcher::new(&devices, opts.key_memory_span, debug);
 
     // show key strokes
     if opts.show_keys {
         eprintln!("Showing codes of key strokes received (Ctrl-C to abort)");
-        while let Some(k) = ev_rx.recv().await {
-            if let Some(name) = key_name_from_code(k) {
-                eprint!("{} ",name);
-            } else {
-                eprint!("{}", k);
+        loop {
+            tokio::select! {
+                ev = ev_rx.recv() => {
+                    let Some(ev) = ev else {
+                        if let Ok(err) = fail_rx.try_recv() {
+                            eprintln!("{:#}", err);
+                        }
+                        exit_code = ExitCode::FAILURE;
+                        break;
+                    };
+                    if ev.state != KeyState::Pressed {
+                        continue;
+                    }
+                    if devices.len() > 1 {
+                        eprint!("{}:", dispatcher.device_name(&ev));
+                    }
+                    if let Some(name) = key_name_from_code(ev.code) {
+                        eprint!("{} ",name);
+                    } else {
+                        eprint!("{} ", ev.code);
+                    }
+                },
+                Some(err) = fail_rx.recv() => {
+                    eprintln!("{:#}", err);
+                    exit_code = ExitCode::FAILURE;
+                    break;
+                },
+                _ = &mut terminated => break,
             }
         }
-        return Ok(ExitCode::SUCCESS);
-    }
-
-    {
-        let mut seq : Vec<u16> = vec![];
-        let mut now = Instant::now();
-        let quit = kv.get_str("quit_command");
-
-        if opts.debug {
-            kt.dump();
+    } else {
+        if debug {
+            dispatcher.dump();
         }
 
-        let mut newline = true;
+        // the config is reloaded when the file changes, and on SIGHUP
+        let (reload_tx, mut reload_rx) = mpsc::channel::<()>(1);
+        let mut watcher = tokio::spawn(watch_config(sources.clone(), reload_tx.clone()));
+        let mut sighup = signal(SignalKind::hangup()).context("Installing SIGHUP handler")?;
+
+        'event_loop:
         loop {
-            if let Some(k) = ev_rx.recv().await {
-                if now.elapsed().as_secs_f32() > opts.key_memory_span && !newline {
-                    seq.clear();
-                    if opts.debug {
-                        eprintln!("... aborted");
-                    }
-                }
-                if opts.debug {
-                    if let Some(name) = key_name_from_code(k) {
-                        eprint!("{} ",name);
-                    } else {
-                        eprint!("k={}", k);
+            let deadline = dispatcher.deadline();
+            let mut reload = false;
+            let triggers = tokio::select! {
+                ev = ev_rx.recv() => {
+                    let Some(ev) = ev else {
+                        match fail_rx.try_recv() {
+                            Ok(err) => eprintln!("{:#}", err),
+                            Err(_) => eprintln!("Device supervisors stopped unexpectedly"),
+                        }
+                        exit_code = ExitCode::FAILURE;
+                        break;
+                    };
+                    dispatcher.handle(&ev)
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    dispatcher.expire()
+                },
+                Some(err) = fail_rx.recv() => {
+                    eprintln!("{:#}", err);
+                    exit_code = ExitCode::FAILURE;
+                    break;
+                },
+                _ = &mut terminated => {
+                    if debug {
+                        eprintln!("-> terminated by signal");
                     }
-                }
-                now = Instant::now();
-                seq.push(k);
+                    break;
+                },
+                Some(()) = reload_rx.recv() => {
+                    reload = true;
+                    vec![]
+                },
+                _ = sighup.recv() => {
+                    reload = true;
+                    vec![]
+                },
+            };
 
-                if let Some(cmd) = kt.find(&KeySequence::from(&seq)) {
-                    if quit.is_some() && cmd == quit.unwrap() {
-                        if opts.debug {
+            // mode switches and resets are handled by the dispatcher
+            // itself
+            for trigger in triggers {
+                let env = trigger.env();
+                for action in trigger.binding.action.items() {
+                    // the quit_command is still supported
+                    let quits = match action {
+                        Action::Quit => true,
+                        Action::Command(cmd) => quit.as_ref().is_some_and(|quit| cmd == quit),
+                        _ => false,
+                    };
+                    if quits {
+                        if debug {
                             eprintln!("-> exiting...");
                         }
-                        break;
+                        break 'event_loop;
                     }
-                    if opts.debug {
-                        eprintln!("-> executing <{}>", cmd);
+                    match action {
+                        Action::Command(cmd) => {
+                            if debug {
+                                eprintln!("-> executing <{}>", cmd);
+                            }
+                            // waiting for a command must not keep a
+                            // signal from terminating keybuddy
+                            tokio::select! {
+                                _ = executor.run(cmd, &trigger.binding, &env) => {},
+                                _ = &mut terminated => break 'event_loop,
+                            }
+                        },
+                        Action::Type(text) => executor.type_text(text, &env).await,
+                        Action::Keys(combos) => executor.send_keys(combos).await,
+                        Action::Sleep(duration) => tokio::select! {
+                            _ = sleep(*duration) => {},
+                            _ = &mut terminated => break 'event_loop,
+                        },
+                        Action::Log(message) => eprintln!("{}", message),
+                        Action::Reload => reload = true,
+                        Action::Quit | Action::Mode(_) | Action::Reset | Action::List(_) => {},
                     }
-                    exec_command(cmd);
-                    seq.clear();
-                    newline = true;
-                } else {
-                    newline = false;
                 }
-            } else {
-                break;
+            }
+
+            // the new keymap replaces the old one, but the devices
+            // are kept; if the file has errors or the devices have
+            // changed, the old keymap stays
+            if reload {
+                match load_config(&files, true) {
+                    Ok(config) => {
+                        let new_quit = config.kv.get_str("quit_command").map(|x| x.to_string());
+                        let kv = config.kv.clone();
+                        let config_sources = config.sources.clone();
+                        let new_devices = config.into_devices();
+                        // the readers tag key events with the index
+                        // of their device, and the supervisors keep
+                        // their filters, so the devices must stay the
+                        // same
+                        if !same_devices(&devices, &new_devices) {
+                            eprintln!("The [device] blocks or their device settings have changed, keeping the current config; restart keybuddy to apply this");
+                            continue;
+                        }
+                        quit = new_quit;
+                        executor.configure(&kv);
+                        dispatcher = Dispatcher::new(&new_devices, opts.key_memory_span, debug);
+                        eprintln!("Reloaded {}", files.join(", "));
+                        // included files may have changed
+                        if config_sources != sources {
+                            watcher.abort();
+                            watcher = tokio::spawn(watch_config(config_sources.clone(), reload_tx.clone()));
+                            sources = config_sources;
+                        }
+                        if debug {
+                            dispatcher.dump();
+                        }
+                    },
+                    Err(err) => eprintln!("Could not reload config, keeping the current one: {:#}", err),
+                }
             }
         }
     }
 
-    stop_tx.send(()).await.unwrap();
-    task_handle.await.unwrap();
+    for (stop_tx, _) in &tasks {
+        let _ = stop_tx.send(()).await;
+    }
+    for (_, task_handle) in tasks {
+        let _ = task_handle.await;
+    }
 
-    Ok(ExitCode::SUCCESS)
+    Ok(exit_code)
 }
@@ -0,0 +1,585 @@
+//! TOML config files, and conversion between the config formats
+//!
+//! A config file whose name ends in `.toml` is read as TOML instead of
+//! the line format. Both define the same settings, bindings, modes
+//! and devices:
+//!
+//! ```toml
+//! include = ["conf.d/*.toml"]
+//!
+//! [settings]
+//! delay = 1.5
+//!
+//! [[binding]]
+//! keys = ["KEY_KP1", "KEY_KP2:long"]
+//! command = "firefox"
+//! run = "restart"
+//!
+//! [[mode]]
+//! name = "media"
+//! timeout = 5.0
+//!
+//! [[mode.binding]]
+//! keys = ["<digit>"]
+//! action = 'type("{1}"); mode("default")'
+//!
+//! [[device]]
+//! name = "numpad"
+//! settings = { vid = 0x1234, pid = 0x5678 }
+//! ```
+//!
+//! Key steps and actions are written as in the line format.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use toml::Spanned;
+use anyhow::{Result, Context, bail};
+
+use crate::action::Action;
+use crate::config::{begin_device, begin_mode, define_binding, define_setting, include, init_from_file, is_toml,
+                    parse_step, seconds, Config, ConfigError, KeyValueData, KeyValueStore, ModeConfig, ParseState,
+                    DEFAULT_MODE};
+use crate::key_tree::{Binding, BoundSequence, KTree, KeyStep, RunMode};
+
+/// Content of a TOML config file
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlFile {
+    /// files read before the definitions of this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<Spanned<String>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    settings: BTreeMap<String, Spanned<toml::Value>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    binding: Vec<TomlBinding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mode: Vec<TomlMode>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    device: Vec<TomlDevice>,
+}
+
+/// A `[[device]]` table, like a `[device]` block
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlDevice {
+    /// generated if missing
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    settings: BTreeMap<String, Spanned<toml::Value>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    binding: Vec<TomlBinding>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mode: Vec<TomlMode>,
+}
+
+/// A `[[mode]]` table, like a `[mode]` block
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlMode {
+    name: Spanned<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<Spanned<f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oneshot: Option<Spanned<bool>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    binding: Vec<TomlBinding>,
+}
+
+/// A `[[binding]]` table: a key sequence, either a command or an
+/// action, and the binding options
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct TomlBinding {
+    /// the steps of the sequence, e.g. "KEY_KP1+KEY_KP2" or "<digit>"
+    keys: Spanned<Vec<Spanned<String>>>,
+    /// command run by the shell (or directly, with `exec`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<Spanned<String>>,
+    /// action(s) as in the line format, e.g. `mode("media")`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<Spanned<String>>,
+    /// `true`, or the minimum interval in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeat: Option<Spanned<Repeat>>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    exec: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run: Option<Run>,
+    /// seconds, 0 for no timeout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<Spanned<f64>>,
+}
+
+/// The `repeat` option of a binding
+#[derive(Deserialize, Serialize)]
+#[serde(untagged, expecting = "expected true, false or a number of seconds")]
+enum Repeat {
+    Enabled(bool),
+    Interval(f64),
+}
+
+/// The `run` option of a binding (see `RunMode`)
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Run {
+    Detach,
+    Wait,
+    Restart,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// A value without a position in a file
+fn unspanned<T>(value: T) -> Spanned<T> {
+    Spanned::new(0..0, value)
+}
+
+/// A float read from the config, as a TOML float (0.2 rather than
+/// 0.20000000298023224)
+fn float(value: f32) -> f64 {
+    value.to_string().parse().unwrap_or(value as f64)
+}
+
+/// Seconds given in a TOML file
+fn duration(secs: &Spanned<f64>) -> std::result::Result<Duration, (Range<usize>, String)> {
+    seconds(*secs.get_ref() as f32)
+        .ok_or_else(|| (secs.span(), format!("Not a valid number of seconds: {}", secs.get_ref())))
+}
+
+impl TomlBinding {
+    /// The sequence and binding defined
+    ///
+    /// On error, the span of the part at fault is returned along with
+    /// the reason.
+    ///
+    fn to_binding(&self) -> std::result::Result<(Vec<KeyStep>, Binding), (Range<usize>, String)> {
+        if self.keys.get_ref().is_empty() {
+            return Err((self.keys.span(), "No keys given".to_string()));
+        }
+        let mut steps = vec![];
+        for key in self.keys.get_ref() {
+            steps.push(parse_step(key.get_ref()).map_err(|(_, msg)| (key.span(), msg))?);
+        }
+        let action = match (&self.command, &self.action) {
+            (Some(command), None) => Action::Command(command.get_ref().clone()),
+            (None, Some(action)) => Action::parse(action.get_ref())
+                .map_err(|msg| (action.span(), format!("{:#}", msg)))?,
+            _ => return Err((self.keys.span(), "A binding needs either 'command' or 'action'".to_string())),
+        };
+        let mut binding = Binding::new(action);
+        if let Some(repeat) = &self.repeat {
+            match repeat.get_ref() {
+                Repeat::Enabled(enabled) => binding.repeat = *enabled,
+                Repeat::Interval(secs) => {
+                    binding.repeat = true;
+                    binding.repeat_interval = Some(duration(&Spanned::new(repeat.span(), *secs))?);
+                },
+            }
+        }
+        binding.exec = self.exec;
+        binding.mode = match self.run {
+            Some(Run::Wait) => RunMode::Wait,
+            Some(Run::Restart) => RunMode::Restart,
+            Some(Run::Detach) | None => RunMode::Detach,
+        };
+        if let Some(timeout) = &self.timeout {
+            binding.timeout = Some(duration(timeout)?);
+        }
+        Ok((steps, binding))
+    }
+
+    /// The table for the binding of `steps`
+    fn from_binding(steps: &[KeyStep], binding: &Binding) -> Self {
+        let (command, action) = match &binding.action {
+            Action::Command(command) => (Some(unspanned(command.clone())), None),
+            action => (None, Some(unspanned(format!("{:?}", action)))),
+        };
+        let repeat = match (binding.repeat, binding.repeat_interval) {
+            (true, Some(interval)) => Some(Repeat::Interval(float(interval.as_secs_f32()))),
+            (true, None) => Some(Repeat::Enabled(true)),
+            (false, _) => None,
+        };
+        Self {
+            keys: unspanned(steps.iter().map(|step| unspanned(step.to_string())).collect()),
+            command,
+            action,
+            repeat: repeat.map(unspanned),
+            exec: binding.exec,
+            run: match binding.mode {
+                RunMode::Detach => None,
+                RunMode::Wait => Some(Run::Wait),
+                RunMode::Restart => Some(Run::Restart),
+            },
+            timeout: binding.timeout.map(|t| unspanned(float(t.as_secs_f32()))),
+        }
+    }
+}
+
+/// The text of a TOML file, for locating problems
+struct Source<'a> {
+    file: Arc<str>,
+    text: &'a str,
+}
+
+impl Source<'_> {
+    /// Line and column (both starting at 1) of the byte `offset`
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+    }
+
+    /// A problem found at `span`
+    fn error(&self, span: Range<usize>, reason: String) -> ConfigError {
+        let (line, column) = self.position(span.start);
+        ConfigError {
+            file: self.file.to_string(),
+            line,
+            column,
+            reason,
+        }
+    }
+}
+
+/// Define the settings of the current block
+fn define_settings(src: &Source, settings: &BTreeMap<String, Spanned<toml::Value>>,
+                   config: &mut Config, state: &mut ParseState) {
+    for (key, value) in settings {
+        let data = match value.get_ref() {
+            toml::Value::String(text) => KeyValueData::Text(text.clone()),
+            toml::Value::Integer(int) => KeyValueData::Int(*int as isize),
+            toml::Value::Float(float) => KeyValueData::Float(*float as f32),
+            toml::Value::Boolean(on) => KeyValueData::Int(*on as isize),
+            other => {
+                config.errors.push(src.error(value.span(), format!("Unsupported value type: {}", other.type_str())));
+                continue;
+            },
+        };
+        if let Some(msg) = define_setting(config, state, key, data) {
+            config.warnings.push(src.error(value.span(), msg));
+        }
+    }
+}
+
+/// Define the bindings of the current block
+fn define_bindings(src: &Source, bindings: &[TomlBinding], config: &mut Config, state: &mut ParseState) {
+    for table in bindings {
+        match table.to_binding() {
+            Ok((steps, mut binding)) => {
+                binding.origin = Some((src.file.clone(), src.position(table.keys.span().start).0));
+                if let Some(msg) = define_binding(config, state, &steps, binding) {
+                    config.warnings.push(src.error(table.keys.span(), msg));
+                }
+            },
+            Err((span, msg)) => config.errors.push(src.error(span, msg)),
+        }
+    }
+}
+
+/// Define the modes of the current device (or of all devices)
+fn define_modes(src: &Source, modes: &[TomlMode], config: &mut Config, state: &mut ParseState) {
+    for mode in modes {
+        if mode.name.get_ref().is_empty() {
+            config.errors.push(src.error(mode.name.span(), "Mode without name".to_string()));
+            continue;
+        }
+        begin_mode(config, state, mode.name.get_ref());
+        if let Some(timeout) = &mode.timeout {
+            if let Some(msg) = define_setting(config, state, "timeout", KeyValueData::Float(*timeout.get_ref() as f32)) {
+                config.warnings.push(src.error(timeout.span(), msg));
+            }
+        }
+        if let Some(oneshot) = &mode.oneshot {
+            if let Some(msg) = define_setting(config, state, "oneshot", KeyValueData::Int(*oneshot.get_ref() as isize)) {
+                config.warnings.push(src.error(oneshot.span(), msg));
+            }
+        }
+        define_bindings(src, &mode.binding, config, state);
+    }
+    begin_mode(config, state, DEFAULT_MODE);
+}
+
+/// Read the definitions of the TOML file `path`, whose content is
+/// `text` (see `read_file`)
+///
+/// If the file is not valid TOML or does not match the schema, a
+/// single error is recorded and nothing in it is defined.
+///
+pub fn read_toml(text: &str, path: &Path, config: &mut Config, state: &mut ParseState, stack: &mut Vec<PathBuf>) {
+    let src = Source {
+        file: Arc::from(path.to_string_lossy().as_ref()),
+        text,
+    };
+    let file : TomlFile = match toml::from_str(text) {
+        Ok(file) => file,
+        Err(err) => {
+            config.errors.push(src.error(err.span().unwrap_or(0..0), err.message().trim().replace('\n', ", ")));
+            return;
+        },
+    };
+    for pattern in &file.include {
+        for problem in include(pattern.get_ref(), path, config, state, stack) {
+            config.errors.push(src.error(pattern.span(), problem));
+        }
+    }
+    define_settings(&src, &file.settings, config, state);
+    define_bindings(&src, &file.binding, config, state);
+    define_modes(&src, &file.mode, config, state);
+    for device in &file.device {
+        begin_device(config, state, device.name.as_deref().unwrap_or(""));
+        define_settings(&src, &device.settings, config, state);
+        define_bindings(&src, &device.binding, config, state);
+        define_modes(&src, &device.mode, config, state);
+    }
+}
+
+/// The settings of `kv` that differ from those of `base`
+fn settings_of(kv: &KeyValueStore, base: Option<&KeyValueStore>) -> BTreeMap<String, Spanned<toml::Value>> {
+    let mut settings = BTreeMap::new();
+    for (key, data) in &kv.0 {
+        if base.is_some_and(|base| base.0.get(key) == Some(data)) {
+            continue;
+        }
+        let value = match data {
+            KeyValueData::Float(float_value) => toml::Value::Float(float(*float_value)),
+            KeyValueData::Int(int) => toml::Value::Integer(*int as i64),
+            KeyValueData::Text(text) => toml::Value::String(text.clone()),
+            // TOML has no null
+            KeyValueData::None => continue,
+        };
+        settings.insert(key.clone(), unspanned(value));
+    }
+    settings
+}
+
+/// Position of the definition of a binding among the files `sources`
+fn origin_order(bound: &BoundSequence, sources: &[PathBuf]) -> (usize, usize) {
+    match &bound.binding.origin {
+        Some((file, line)) => {
+            let index = sources.iter().position(|s| s.to_string_lossy() == **file);
+            (index.unwrap_or(usize::MAX), *line)
+        },
+        None => (usize::MAX, usize::MAX),
+    }
+}
+
+/// The bindings of `tree` that are not inherited from `base`, in the
+/// order they were defined
+fn bindings_of(tree: &KTree, base: Option<&KTree>, sources: &[PathBuf]) -> Vec<TomlBinding> {
+    let inherited = base.map(|base| base.bindings()).unwrap_or_default();
+    let mut bound : Vec<BoundSequence> = tree.bindings()
+        .into_iter()
+        .filter(|b| !inherited.iter().any(|i| i.steps == b.steps && i.binding == b.binding))
+        .collect();
+    bound.sort_by_key(|b| origin_order(b, sources));
+    bound.iter().map(|b| TomlBinding::from_binding(&b.steps, b.binding)).collect()
+}
+
+/// A number given as an integer or a float
+fn number(value: &toml::Value) -> Option<f64> {
+    value.as_float().or(value.as_integer().map(|int| int as f64))
+}
+
+/// The modes of `modes` with their settings and bindings that are not
+/// inherited from `base`
+fn modes_of(modes: &[ModeConfig], base: &[ModeConfig], sources: &[PathBuf]) -> Vec<TomlMode> {
+    let mut tables = vec![];
+    for mode in modes {
+        let inherited = base.iter().find(|m| m.name == mode.name);
+        let settings = settings_of(&mode.kv, inherited.map(|m| &m.kv));
+        let table = TomlMode {
+            name: unspanned(mode.name.clone()),
+            timeout: settings.get("timeout").and_then(|v| number(v.get_ref())).map(unspanned),
+            oneshot: settings.get("oneshot").map(|v| unspanned(number(v.get_ref()) != Some(0.0))),
+            binding: bindings_of(&mode.tree, inherited.map(|m| &m.tree), sources),
+        };
+        if inherited.is_none() || table.timeout.is_some() || table.oneshot.is_some() || !table.binding.is_empty() {
+            tables.push(table);
+        }
+    }
+    tables
+}
+
+/// The definitions of `config` as a single TOML file
+///
+/// Each device only lists what differs from the definitions outside of
+/// any device.
+///
+fn from_config(config: &Config) -> TomlFile {
+    TomlFile {
+        include: vec![],
+        settings: settings_of(&config.kv, None),
+        binding: bindings_of(&config.tree, None, &config.sources),
+        mode: modes_of(&config.modes, &[], &config.sources),
+        device: config.devices
+            .iter()
+            .map(|dev| TomlDevice {
+                name: Some(dev.name.clone()),
+                settings: settings_of(&dev.kv, Some(&config.kv)),
+                binding: bindings_of(&dev.tree, Some(&config.tree), &config.sources),
+                mode: modes_of(&dev.modes, &config.modes, &config.sources),
+            })
+            .collect(),
+    }
+}
+
+/// Write `config` as a TOML file
+pub fn to_toml(config: &Config) -> Result<String> {
+    toml::to_string(&from_config(config)).context("Could not write the config as TOML")
+}
+
+/// Append `line` to `out`, which is in the line format and therefore
+/// cannot hold line breaks within a definition
+fn write_line(out: &mut String, line: String) -> Result<()> {
+    if line.contains(['\n', '\r']) {
+        bail!("Cannot write a line break in this format: {}", line.escape_default());
+    }
+    out.push_str(&line);
+    out.push('\n');
+    Ok(())
+}
+
+/// Write settings and bindings in the line format
+fn write_block(out: &mut String, settings: &BTreeMap<String, Spanned<toml::Value>>,
+               bindings: &[TomlBinding]) -> Result<()> {
+    for (key, value) in settings {
+        let value = match value.get_ref() {
+            toml::Value::String(text) => format!("\"{}\"", text),
+            toml::Value::Integer(int) if key == "vid" || key == "pid" => format!("0x{:04x}", int),
+            toml::Value::Float(float) => format!("{:?}", float),
+            toml::Value::Boolean(on) => (*on as isize).to_string(),
+            other => other.to_string(),
+        };
+        write_line(out, format!("{} = {}", key, value))?;
+    }
+    for (steps, binding) in bindings.iter().filter_map(|b| b.to_binding().ok()) {
+        let steps : Vec<String> = steps.iter().map(|s| s.to_string()).collect();
+        write_line(out, format!("{} => {:?}", steps.join(", "), binding))?;
+    }
+    Ok(())
+}
+
+/// Write modes in the line format
+fn write_modes(out: &mut String, modes: &[TomlMode]) -> Result<()> {
+    for mode in modes {
+        out.push('\n');
+        write_line(out, format!("[mode {}]", mode.name.get_ref()))?;
+        if let Some(timeout) = &mode.timeout {
+            write_line(out, format!("timeout = {:?}", timeout.get_ref()))?;
+        }
+        if let Some(oneshot) = &mode.oneshot {
+            write_line(out, format!("oneshot = {}", *oneshot.get_ref() as isize))?;
+        }
+        write_block(out, &BTreeMap::new(), &mode.binding)?;
+    }
+    Ok(())
+}
+
+/// Write `config` as a file in the line format
+///
+/// Fails if a string contains a line break, which this format cannot
+/// express.
+///
+pub fn to_legacy(config: &Config) -> Result<String> {
+    let file = from_config(config);
+    let mut out = String::new();
+    write_block(&mut out, &file.settings, &file.binding)?;
+    write_modes(&mut out, &file.mode)?;
+    for dev in &file.device {
+        out.push('\n');
+        write_line(&mut out, format!("[device {}]", dev.name.as_deref().unwrap_or("")))?;
+        write_block(&mut out, &dev.settings, &dev.binding)?;
+        write_modes(&mut out, &dev.mode)?;
+    }
+    Ok(out)
+}
+
+/// Convert the config file `input` to the format of `output`, or to
+/// the other format if no output is given, in which case the result is
+/// written to stdout
+///
+/// Included files end up in the result, and comments are lost.
+///
+pub fn convert(input: &str, output: Option<&str>) -> Result<()> {
+    let mut config = Config::new();
+    init_from_file(input, &mut config)?;
+    for error in config.errors.iter().chain(&config.warnings) {
+        eprintln!("{}", error);
+    }
+    if !config.errors.is_empty() {
+        bail!("{} invalid line(s) in {}, not converting", config.errors.len(), input);
+    }
+    let toml = match output {
+        Some(output) => is_toml(Path::new(output)),
+        None => !is_toml(Path::new(input)),
+    };
+    let text = if toml { to_toml(&config)? } else { to_legacy(&config)? };
+    match output {
+        Some(output) => {
+            if Path::new(output).exists() {
+                bail!("{} already exists", output);
+            }
+            std::fs::write(output, text).context(format!("Could not write {}", output))?;
+        },
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read `text` as a config file with the extension `ext`
+    fn read(name: &str, ext: &str, text: &str) -> Config {
+        let path = std::env::temp_dir().join(format!("keybuddy-test-{}-{}.{}", std::process::id(), name, ext));
+        std::fs::write(&path, text).unwrap();
+        let mut config = Config::new();
+        init_from_file(&path.to_string_lossy(), &mut config).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(config.errors.is_empty(), "{:?}", config.errors);
+        config
+    }
+
+    #[test]
+    fn round_trip() {
+        let legacy = concat!(
+            "delay = 1.5\n",
+            "shell = \"/bin/bash\"\n",
+            "vid = 0x1234\n",
+            "KEY_KP1, KEY_KP2 => \"firefox\" [restart]\n",
+            "KEY_KP3:long => \"printf 'a\tb' | grep \\\"a\\\\\\\\b\\\"\" [repeat=0.2, exec, timeout=10]\n",
+            "KEY_KP4 => mode(\"media\")\n",
+            "KEY_KP5 => key(\"KEY_LEFTCTRL+KEY_T\"); sleep(100); type(\"\tx\")\n",
+            "<digit>, KEY_KPENTER => log(\"digit {1}\") [wait]\n",
+            "\n",
+            "[mode media]\n",
+            "timeout = 5.0\n",
+            "oneshot = 1\n",
+            "KEY_KP0 => reset\n",
+            "\n",
+            "[device pad]\n",
+            "pid = 0x5678\n",
+            "KEY_KP1, KEY_KP2 => \"chromium\"\n",
+        );
+        let config = read("legacy", "conf", legacy);
+        assert_eq!(to_legacy(&config).unwrap(), legacy);
+
+        let toml = to_toml(&config).unwrap();
+        let config = read("toml", "toml", &toml);
+        assert_eq!(to_legacy(&config).unwrap(), legacy);
+        assert_eq!(to_toml(&config).unwrap(), toml);
+    }
+
+    #[test]
+    fn line_break() {
+        let config = read("break", "toml", "[[binding]]\nkeys = [\"KEY_KP1\"]\ncommand = \"a\\nb\"\n");
+        assert!(to_legacy(&config).is_err());
+    }
+}
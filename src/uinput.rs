@@ -0,0 +1,230 @@
+//! Virtual keyboard based on the kernel's uinput module
+//!
+//! Key strokes written to the virtual keyboard reach the focused
+//! application like those of a real keyboard, under X11 as well as
+//! Wayland or on the console. Text is typed assuming a US keyboard
+//! layout.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use byteorder::{NativeEndian, WriteBytesExt};
+use anyhow::{Result, Context, bail};
+
+use crate::key_codes::{all_key_codes, code_from_key_name, key_name_from_code, EventType, RawCodes};
+
+/// Device through which virtual input devices are created
+const UINPUT_DEVICE: &str = "/dev/uinput";
+
+/// Name of the virtual keyboard, also used to keep keybuddy from
+/// reading its own key strokes
+pub const VIRTUAL_KEYBOARD_NAME: &str = "keybuddy virtual keyboard";
+
+/// ioctl requests from /usr/include/linux/uinput.h
+const UI_DEV_CREATE: libc::c_ulong = 0x5501;        // _IO('U', 1)
+const UI_DEV_DESTROY: libc::c_ulong = 0x5502;       // _IO('U', 2)
+const UI_DEV_SETUP: libc::c_ulong = 0x405c5503;     // _IOW('U', 3, struct uinput_setup)
+const UI_SET_EVBIT: libc::c_ulong = 0x40045564;     // _IOW('U', 100, int)
+const UI_SET_KEYBIT: libc::c_ulong = 0x40045565;    // _IOW('U', 101, int)
+
+/// Bus type reported for the virtual keyboard (BUS_VIRTUAL)
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// struct uinput_setup
+#[repr(C)]
+struct UinputSetup {
+    /// bustype, vendor, product, version
+    id: [u16; 4],
+    name: [u8; 80],
+    ff_effects_max: u32,
+}
+
+/// Parse key combinations like `ctrl+shift+t` or `KEY_LEFTALT+KEY_F4`
+///
+/// Several combinations are separated by spaces and are sent one
+/// after the other. Keys are given by their names, with or without
+/// `KEY_` and in any case; `ctrl`, `shift`, `alt`, `altgr` and `meta`
+/// (or `super`) stand for the modifiers.
+///
+pub fn parse_combos(text: &str) -> Result<Vec<Vec<u16>>> {
+    let mut combos = vec![];
+    for combo in text.split_whitespace() {
+        let mut keys = vec![];
+        for name in combo.split('+') {
+            let key = match name.to_lowercase().as_str() {
+                "ctrl" | "control" => RawCodes::KEY_LEFT_CTRL,
+                "shift" => RawCodes::KEY_LEFT_SHIFT,
+                "alt" => RawCodes::KEY_LEFT_ALT,
+                "altgr" => RawCodes::KEY_RIGHT_ALT,
+                "meta" | "super" => RawCodes::KEY_LEFT_META,
+                _ => {
+                    let upper = name.to_uppercase();
+                    let full = if upper.starts_with("KEY_") { upper } else { format!("KEY_{}", upper) };
+                    match code_from_key_name(&full) {
+                        Some(key) => key,
+                        None => bail!("Unknown key '{}' in <{}>", name, text),
+                    }
+                },
+            };
+            keys.push(key);
+        }
+        combos.push(keys);
+    }
+    if combos.is_empty() {
+        bail!("No keys given");
+    }
+    Ok(combos)
+}
+
+/// Show key combinations as accepted by `parse_combos`
+pub fn format_combos(combos: &[Vec<u16>]) -> String {
+    let combos : Vec<String> = combos
+        .iter()
+        .map(|keys| keys
+             .iter()
+             .map(|k| key_name_from_code(*k).map_or(k.to_string(), |name| name.to_string()))
+             .collect::<Vec<_>>()
+             .join("+"))
+        .collect();
+    combos.join(" ")
+}
+
+/// The key that produces `ch` on a US keyboard, and whether Shift has
+/// to be held
+fn key_for_char(ch: char) -> Option<(u16, bool)> {
+    let (name, shift) = match ch {
+        'a'..='z' | '0'..='9' => (ch.to_ascii_uppercase().to_string(), false),
+        'A'..='Z' => (ch.to_string(), true),
+        ' ' => ("SPACE".to_string(), false),
+        '\n' => ("ENTER".to_string(), false),
+        '\t' => ("TAB".to_string(), false),
+        _ => {
+            let (name, shift) = match ch {
+                '-' => ("MINUS", false),         '_' => ("MINUS", true),
+                '=' => ("EQUAL", false),         '+' => ("EQUAL", true),
+                '[' => ("LEFTBRACE", false),     '{' => ("LEFTBRACE", true),
+                ']' => ("RIGHTBRACE", false),    '}' => ("RIGHTBRACE", true),
+                ';' => ("SEMICOLON", false),     ':' => ("SEMICOLON", true),
+                '\'' => ("APOSTROPHE", false),   '"' => ("APOSTROPHE", true),
+                '`' => ("GRAVE", false),         '~' => ("GRAVE", true),
+                '\\' => ("BACKSLASH", false),    '|' => ("BACKSLASH", true),
+                ',' => ("COMMA", false),         '<' => ("COMMA", true),
+                '.' => ("DOT", false),           '>' => ("DOT", true),
+                '/' => ("SLASH", false),         '?' => ("SLASH", true),
+                '!' => ("1", true),              '@' => ("2", true),
+                '#' => ("3", true),              '$' => ("4", true),
+                '%' => ("5", true),              '^' => ("6", true),
+                '&' => ("7", true),              '*' => ("8", true),
+                '(' => ("9", true),              ')' => ("0", true),
+                _ => return None,
+            };
+            (name.to_string(), shift)
+        },
+    };
+    code_from_key_name(&format!("KEY_{}", name)).map(|key| (key, shift))
+}
+
+/// A virtual keyboard; it disappears when dropped
+pub struct VirtualKeyboard {
+    file: File,
+}
+
+impl VirtualKeyboard {
+    /// Create the virtual keyboard, with all keys known to keybuddy
+    ///
+    /// This requires write access to /dev/uinput. Applications may
+    /// only notice the new device after a short while.
+    ///
+    pub fn new() -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(UINPUT_DEVICE)
+            .context(format!("Could not open {}", UINPUT_DEVICE))?;
+        let fd = file.as_raw_fd();
+
+        let mut setup = UinputSetup {
+            id: [BUS_VIRTUAL, 0, 0, 1],
+            name: [0; 80],
+            ff_effects_max: 0,
+        };
+        setup.name[..VIRTUAL_KEYBOARD_NAME.len()].copy_from_slice(VIRTUAL_KEYBOARD_NAME.as_bytes());
+
+        unsafe {
+            if libc::ioctl(fd, UI_SET_EVBIT, EventType::EvKey as libc::c_int) < 0
+                || libc::ioctl(fd, UI_SET_EVBIT, EventType::EvSyn as libc::c_int) < 0 {
+                bail!("Could not enable key events: {}", std::io::Error::last_os_error());
+            }
+            for key in all_key_codes() {
+                if libc::ioctl(fd, UI_SET_KEYBIT, key as libc::c_int) < 0 {
+                    bail!("Could not enable key {}: {}", key, std::io::Error::last_os_error());
+                }
+            }
+            if libc::ioctl(fd, UI_DEV_SETUP, &setup as *const UinputSetup) < 0 {
+                bail!("Could not set up virtual keyboard: {}", std::io::Error::last_os_error());
+            }
+            if libc::ioctl(fd, UI_DEV_CREATE) < 0 {
+                bail!("Could not create virtual keyboard: {}", std::io::Error::last_os_error());
+            }
+        }
+        Ok(Self { file })
+    }
+
+    /// Write one input event (the kernel sets the time stamp)
+    fn emit(&mut self, evtype: EventType, code: u16, value: i32) -> Result<()> {
+        let mut packet = Vec::with_capacity(24);
+        packet.write_u64::<NativeEndian>(0)?;
+        packet.write_u64::<NativeEndian>(0)?;
+        packet.write_u16::<NativeEndian>(evtype as u16)?;
+        packet.write_u16::<NativeEndian>(code)?;
+        packet.write_i32::<NativeEndian>(value)?;
+        self.file.write_all(&packet).context("Could not write to virtual keyboard")?;
+        Ok(())
+    }
+
+    /// Press `key` (value 1) or release it (value 0), and report it
+    fn key(&mut self, key: u16, value: i32) -> Result<()> {
+        self.emit(EventType::EvKey, key, value)?;
+        self.emit(EventType::EvSyn, 0, 0)
+    }
+
+    /// Press the keys of a combination in order, and release them in
+    /// reverse order
+    pub fn press_combo(&mut self, keys: &[u16]) -> Result<()> {
+        for key in keys {
+            self.key(*key, 1)?;
+        }
+        for key in keys.iter().rev() {
+            self.key(*key, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Type `text` (US keyboard layout)
+    ///
+    /// Characters that cannot be typed are skipped and reported in the
+    /// error returned.
+    ///
+    pub fn type_text(&mut self, text: &str) -> Result<()> {
+        let mut skipped = String::new();
+        for ch in text.chars() {
+            match key_for_char(ch) {
+                Some((key, true)) => self.press_combo(&[RawCodes::KEY_LEFT_SHIFT, key])?,
+                Some((key, false)) => self.press_combo(&[key])?,
+                None => skipped.push(ch),
+            }
+        }
+        if !skipped.is_empty() {
+            bail!("Could not type these characters: {:?}", skipped);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for VirtualKeyboard {
+    /// Remove the virtual keyboard
+    fn drop(&mut self) {
+        unsafe { libc::ioctl(self.file.as_raw_fd(), UI_DEV_DESTROY) };
+    }
+}
@@ -11,6 +11,10 @@ use anyhow::{Result, Context, bail};
 
 /// collected information for devices listed by `xinput`
 ///
+/// The same record is also filled in by the native evdev discovery
+/// (see `evdev.rs`); fields that have no meaning there are left at
+/// their defaults.
+///
 #[derive(Debug, Clone)]
 pub struct XinputEntry {
     pub name: String,
@@ -18,8 +22,8 @@ pub struct XinputEntry {
     pub keyboard: bool,
     pub pointer: bool,
     pub slave: bool,
-    pub master: bool,
     pub floating: bool,
+    pub attached_to: Option<usize>,
     pub device: String,
     pub usb_vid: u16,
     pub usb_pid: u16,
@@ -30,16 +34,27 @@ impl XinputEntry {
         static RX: OnceLock<Regex> = OnceLock::new();
         let rx = RX.get_or_init(
             || Regex::new(r"^[^A-Za-z]+(?<name>.+?)\s+id=(?<id>[0-9]+)\s+\[(?<slave>.+)\]").unwrap());
+        static RX_MASTER: OnceLock<Regex> = OnceLock::new();
+        let rx_master = RX_MASTER.get_or_init(
+            || Regex::new(r"\((?<master>[0-9]+)\)").unwrap());
         if let Some(caps) = rx.captures(line) {
             let slave = &caps["slave"];
+            // slaves list the id of the master they are attached to
+            let attached_to = if slave.contains("slave") {
+                rx_master
+                    .captures(slave)
+                    .and_then(|m| m["master"].parse::<usize>().ok())
+            } else {
+                None
+            };
             Some(Self {
                 name: caps["name"].to_string(),
                 id: caps["id"].parse::<usize>().unwrap_or(0),
                 keyboard: slave.contains("keyboard"),
                 pointer: slave.contains("pointer"),
                 slave: slave.contains("slave"),
-                master: slave.contains("master"),
                 floating: slave.contains("floating"),
+                attached_to,
                 device: "".to_string(),
                 usb_pid: 0,
                 usb_vid: 0,